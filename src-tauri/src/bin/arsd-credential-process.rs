@@ -0,0 +1,167 @@
+//! `credential_process` helper that hands out role credentials using the SSO session held by arsd.
+//!
+//! Add it to a profile in `~/.aws/config`:
+//!
+//! ```ini
+//! [profile sandbox]
+//! credential_process = arsd-credential-process --partition us-west-2-d-123abc --account 999888777666 --role ReadOnly
+//! ```
+use std::process::exit;
+
+use chrono::{DateTime, Utc};
+
+use arsd::configuration::get_configuration;
+use arsd::domain::storage::{app_config_dir, app_data_dir};
+use arsd::secrets::SecretStore;
+use arsd::session::account;
+use arsd::sql;
+
+const USAGE: &str =
+    "usage: arsd-credential-process --partition <slug> --account <id> --role <name> [--config <path>]";
+
+// https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-sourcing-external.html
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ProcessCredentials {
+    version: u8,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+    expiration: DateTime<Utc>,
+}
+
+struct Args {
+    partition: String,
+    account_id: String,
+    role_name: String,
+    config: Option<std::path::PathBuf>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut partition = None;
+    let mut account_id = None;
+    let mut role_name = None;
+    let mut config = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = match flag.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            _ => args
+                .next()
+                .ok_or_else(|| format!("missing value for {}", flag))?,
+        };
+        match flag.as_str() {
+            "--partition" => partition = Some(value),
+            "--account" => account_id = Some(value),
+            "--role" => role_name = Some(value),
+            "--config" => config = Some(value.into()),
+            _ => return Err(format!("unknown argument {}", flag)),
+        }
+    }
+    Ok(Args {
+        partition: partition.ok_or("--partition is required")?,
+        account_id: account_id.ok_or("--account is required")?,
+        role_name: role_name.ok_or("--role is required")?,
+        config,
+    })
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("arsd-credential-process: {}", message);
+    exit(1);
+}
+
+#[tokio::main]
+async fn main() {
+    let args = parse_args().unwrap_or_else(|e| fail(format!("{}\n{}", e, USAGE)));
+
+    let config_path = match args.config {
+        Some(p) => p,
+        None => app_config_dir()
+            .unwrap_or_else(|| fail("could not find the arsd config directory".to_string()))
+            .join("config.yaml"),
+    };
+    let settings = get_configuration(config_path.clone()).unwrap_or_else(|e| {
+        fail(format!(
+            "failed to load configuration from {:?}: {}",
+            config_path, e
+        ))
+    });
+    let partition = settings
+        .partition(args.partition.clone())
+        .unwrap_or_else(|| fail(format!("no partition found for {}", args.partition)));
+
+    let data_dir = app_data_dir()
+        .unwrap_or_else(|| fail("could not find the arsd data directory".to_string()));
+    // the app owns the database and its key, so never create or migrate either from here
+    let db = sql::database::open_existing(&data_dir)
+        .unwrap_or_else(|e| fail(format!("failed to open the arsd database: {}", e)));
+    let key_dir = app_config_dir()
+        .unwrap_or_else(|| fail("could not find the arsd config directory".to_string()));
+    let secrets = SecretStore::open_existing(&settings, &key_dir)
+        .unwrap_or_else(|e| fail(format!("failed to load the database key: {}", e)));
+    let cached = sql::models::RoleCredentials::find(
+        &db,
+        &secrets,
+        partition.slug(),
+        args.account_id.clone(),
+        args.role_name.clone(),
+        settings.credential_margin(),
+    )
+    .unwrap_or_else(|e| fail(format!("failed to read cached credentials: {}", e)));
+
+    let creds: account::Credentials = match cached {
+        Some(c) => c.into(),
+        None => {
+            let token = match sql::models::Token::find(&db, &secrets, partition.slug()) {
+                Ok(Some(t)) => t,
+                Ok(None) => fail(format!(
+                    "no valid SSO session for {}, open arsd to sign in",
+                    partition.slug()
+                )),
+                Err(e) => fail(format!("failed to read token: {}", e)),
+            };
+            let creds = account::role_credentials(
+                &partition,
+                token.access_token,
+                args.role_name.clone(),
+                args.account_id.clone(),
+            )
+            .await
+            .unwrap_or_else(|e| fail(format!("failed to get role credentials: {}", e)));
+            let cache = sql::models::RoleCredentials {
+                partition: partition.slug(),
+                account_id: args.account_id,
+                role_name: args.role_name,
+                access_key_id: creds.access_key_id.clone(),
+                secret_access_key: creds.secret_access_key.clone(),
+                session_token: creds.session_token.clone(),
+                expires_at: creds.expires_at,
+            };
+            if let Err(e) = cache.insert(&db, &secrets) {
+                // not fatal, the credentials are still good
+                eprintln!(
+                    "arsd-credential-process: failed to cache credentials: {}",
+                    e
+                );
+            }
+            creds
+        }
+    };
+
+    println!(
+        "{}",
+        serde_json::to_string(&ProcessCredentials {
+            version: 1,
+            access_key_id: creds.access_key_id,
+            secret_access_key: creds.secret_access_key,
+            session_token: creds.session_token,
+            expiration: creds.expires_at,
+        })
+        .unwrap()
+    );
+}
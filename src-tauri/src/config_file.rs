@@ -0,0 +1,370 @@
+//! Edits to `config.yaml` made from the app. The file is handled as a plain YAML document and only
+//! the keys being changed are touched, so settings arsd doesn't know about are kept. Comments are
+//! not preserved, so the previous file is kept next to it as `config.yaml.bak`.
+use std::fs;
+use std::path::{Path, PathBuf};
+use url::Url;
+use yaml_rust2::{Yaml, YamlEmitter, YamlLoader};
+
+pub use yaml_rust2::yaml::Hash;
+
+use crate::configuration::get_configuration;
+use crate::error::ArsdError;
+
+/// Read `path` as a YAML mapping. A missing or empty file is an empty mapping.
+pub fn load(path: &Path) -> Result<Hash, ArsdError> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Hash::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut docs = YamlLoader::load_from_str(&text)
+        .map_err(|e| ArsdError::Config(format!("{:?} is not valid YAML: {}", path, e)))?;
+    if docs.len() > 1 {
+        return Err(ArsdError::Config(format!(
+            "{:?} has {} YAML documents, expected one",
+            path,
+            docs.len()
+        )));
+    }
+    match docs.pop() {
+        None | Some(Yaml::Null) => Ok(Hash::new()),
+        Some(Yaml::Hash(h)) => Ok(h),
+        Some(_) => Err(ArsdError::Config(format!(
+            "{:?} should be a mapping of settings",
+            path
+        ))),
+    }
+}
+
+/// Whether saving from the app would drop comments from `path`, so the UI can warn first. Errs on
+/// the side of yes: a `#` inside a quoted value counts too.
+pub fn has_comments(path: &Path) -> bool {
+    fs::read_to_string(path).is_ok_and(|text| {
+        text.lines()
+            .any(|l| l.trim_start().starts_with('#') || l.contains(" #"))
+    })
+}
+
+/// Where the previous version of `path` is kept by `edit`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    path.with_file_name(name)
+}
+
+/// Load `path`, apply `edit` and write the result back. The new file is checked with
+/// `get_configuration` before it replaces the old one, so a bad edit can't leave arsd with a config
+/// it won't start with. A symlinked config is written through the link, and the old contents are
+/// copied to `backup_path` first.
+pub fn edit(
+    path: &Path,
+    edit: impl FnOnce(&mut Hash) -> Result<(), ArsdError>,
+) -> Result<(), ArsdError> {
+    // rename over the link itself would replace it with a regular file
+    let target = match fs::canonicalize(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut doc = load(&target)?;
+    edit(&mut doc)?;
+
+    let mut out = String::new();
+    YamlEmitter::new(&mut out)
+        .dump(&Yaml::Hash(doc))
+        .map_err(|e| ArsdError::Config(format!("Could not write YAML: {:?}", e)))?;
+    out.push('\n');
+
+    if let Some(dir) = target.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let staged = target.with_extension("new.yaml");
+    fs::write(&staged, out)?;
+    if let Err(e) = get_configuration(staged.clone()) {
+        let _ = fs::remove_file(&staged);
+        return Err(ArsdError::Config(format!(
+            "The edited config would not load: {}",
+            e
+        )));
+    }
+    if target.exists() {
+        fs::copy(&target, backup_path(path))?;
+    }
+    fs::rename(&staged, &target)?;
+    Ok(())
+}
+
+fn key(k: &str) -> Yaml {
+    Yaml::String(k.to_string())
+}
+
+/// Remove `name` from `map`. Unquoted account IDs load as integers, so those are matched too.
+fn remove_key(map: &mut Hash, name: &str) {
+    map.remove(&key(name));
+    if let Ok(n) = name.parse::<i64>() {
+        map.remove(&Yaml::Integer(n));
+    }
+}
+
+/// The mapping under `name`, created when it's missing or left empty (`aliases:` with nothing
+/// after it is null).
+fn mapping<'a>(parent: &'a mut Hash, name: &str) -> Result<&'a mut Hash, ArsdError> {
+    let value = parent.entry(key(name)).or_insert(Yaml::Null);
+    if value.is_null() {
+        *value = Yaml::Hash(Hash::new());
+    }
+    match value {
+        Yaml::Hash(h) => Ok(h),
+        _ => Err(ArsdError::Config(format!("`{}` should be a mapping", name))),
+    }
+}
+
+fn partitions(doc: &mut Hash) -> Result<&mut Vec<Yaml>, ArsdError> {
+    let value = doc.entry(key("partitions")).or_insert(Yaml::Null);
+    if value.is_null() {
+        *value = Yaml::Array(vec![]);
+    }
+    match value {
+        Yaml::Array(a) => Ok(a),
+        _ => Err(ArsdError::Config(String::from(
+            "`partitions` should be a list",
+        ))),
+    }
+}
+
+fn same_partition(entry: &Yaml, start_url: &str, region: &str) -> bool {
+    entry["start_url"].as_str() == Some(start_url) && entry["region"].as_str() == Some(region)
+}
+
+pub fn add_partition(
+    doc: &mut Hash,
+    start_url: &str,
+    region: &str,
+    account_id: Option<&str>,
+) -> Result<(), ArsdError> {
+    match Url::parse(start_url) {
+        Ok(u) if u.scheme() == "https" && u.host_str().is_some() => {}
+        _ => {
+            return Err(ArsdError::Config(format!(
+                "{} is not an https start URL",
+                start_url
+            )))
+        }
+    }
+    if region.trim().is_empty() {
+        return Err(ArsdError::Config(String::from("A region is required")));
+    }
+    let partitions = partitions(doc)?;
+    if partitions
+        .iter()
+        .any(|p| same_partition(p, start_url, region))
+    {
+        return Err(ArsdError::Config(format!(
+            "{} in {} is already configured",
+            start_url, region
+        )));
+    }
+    let mut entry = Hash::new();
+    entry.insert(key("start_url"), key(start_url));
+    entry.insert(key("region"), key(region.trim()));
+    if let Some(id) = account_id.filter(|id| !id.is_empty()) {
+        entry.insert(key("account_id"), key(id));
+    }
+    partitions.push(Yaml::Hash(entry));
+    Ok(())
+}
+
+pub fn remove_partition(doc: &mut Hash, start_url: &str, region: &str) -> Result<(), ArsdError> {
+    let partitions = partitions(doc)?;
+    let before = partitions.len();
+    partitions.retain(|p| !same_partition(p, start_url, region));
+    if partitions.len() == before {
+        return Err(ArsdError::Config(format!(
+            "{} in {} is not in the config file",
+            start_url, region
+        )));
+    }
+    Ok(())
+}
+
+/// Which table under `aliases` to change.
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AliasKind {
+    Accounts,
+    Roles,
+}
+
+/// Set the alias for `name`, or remove it with `None`.
+pub fn set_alias(
+    doc: &mut Hash,
+    kind: AliasKind,
+    name: &str,
+    alias: Option<&str>,
+) -> Result<(), ArsdError> {
+    let table = match kind {
+        AliasKind::Accounts => "accounts",
+        AliasKind::Roles => "roles",
+    };
+    let aliases = mapping(mapping(doc, "aliases")?, table)?;
+    remove_key(aliases, name);
+    if let Some(a) = alias.filter(|a| !a.is_empty()) {
+        aliases.insert(key(name), key(a));
+    }
+    Ok(())
+}
+
+/// Replace the tags for `name`. An empty list removes the entry.
+pub fn set_tags(doc: &mut Hash, name: &str, tags: &[String]) -> Result<(), ArsdError> {
+    let all = mapping(doc, "tags")?;
+    remove_key(all, name);
+    if !tags.is_empty() {
+        all.insert(
+            key(name),
+            Yaml::Array(tags.iter().map(|t| key(t)).collect()),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Hash {
+        match YamlLoader::load_from_str(text).unwrap().pop() {
+            Some(Yaml::Hash(h)) => h,
+            other => panic!("expected a mapping, got {:?}", other),
+        }
+    }
+
+    fn dump(doc: &Hash) -> String {
+        let mut out = String::new();
+        YamlEmitter::new(&mut out)
+            .dump(&Yaml::Hash(doc.clone()))
+            .unwrap();
+        out
+    }
+
+    /// A config file in a directory of its own, removed from earlier runs.
+    fn config_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("arsd-config-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("config.yaml")
+    }
+
+    #[test]
+    fn adds_and_removes_partitions() {
+        let mut doc = parse("partitions:\nother: kept\n");
+        add_partition(
+            &mut doc,
+            "https://d-123abc.awsapps.com/start#",
+            "us-west-2",
+            Some("999888777666"),
+        )
+        .unwrap();
+        let partitions = &Yaml::Hash(doc.clone())["partitions"];
+        assert_eq!(partitions[0]["region"].as_str(), Some("us-west-2"));
+        assert_eq!(partitions[0]["account_id"].as_str(), Some("999888777666"));
+        assert!(add_partition(
+            &mut doc,
+            "https://d-123abc.awsapps.com/start#",
+            "us-west-2",
+            None
+        )
+        .is_err());
+        assert!(add_partition(
+            &mut doc,
+            "http://d-123abc.awsapps.com/start#",
+            "us-west-2",
+            None
+        )
+        .is_err());
+
+        remove_partition(&mut doc, "https://d-123abc.awsapps.com/start#", "us-west-2").unwrap();
+        assert_eq!(
+            Yaml::Hash(doc.clone())["partitions"].as_vec().map(Vec::len),
+            Some(0)
+        );
+        assert!(
+            remove_partition(&mut doc, "https://d-123abc.awsapps.com/start#", "us-west-2").is_err()
+        );
+        assert_eq!(Yaml::Hash(doc)["other"].as_str(), Some("kept"));
+    }
+
+    #[test]
+    fn sets_and_clears_aliases() {
+        let mut doc = parse("aliases:\n");
+        set_alias(&mut doc, AliasKind::Accounts, "111122223333", Some("prod")).unwrap();
+        set_alias(
+            &mut doc,
+            AliasKind::Roles,
+            "AdministratorAccess",
+            Some("admin"),
+        )
+        .unwrap();
+        let aliases = &Yaml::Hash(doc.clone())["aliases"];
+        assert_eq!(aliases["accounts"]["111122223333"].as_str(), Some("prod"));
+        assert_eq!(
+            aliases["roles"]["AdministratorAccess"].as_str(),
+            Some("admin")
+        );
+
+        set_alias(&mut doc, AliasKind::Accounts, "111122223333", Some("")).unwrap();
+        set_alias(&mut doc, AliasKind::Roles, "AdministratorAccess", None).unwrap();
+        let aliases = &Yaml::Hash(doc)["aliases"];
+        assert_eq!(aliases["accounts"].as_hash().map(Hash::len), Some(0));
+        assert_eq!(aliases["roles"].as_hash().map(Hash::len), Some(0));
+    }
+
+    #[test]
+    fn sets_and_clears_tags() {
+        let mut doc = Hash::new();
+        set_tags(&mut doc, "a@example.com", &["prod".into(), "team-x".into()]).unwrap();
+        assert_eq!(
+            Yaml::Hash(doc.clone())["tags"]["a@example.com"][1].as_str(),
+            Some("team-x")
+        );
+        set_tags(&mut doc, "a@example.com", &[]).unwrap();
+        assert_eq!(Yaml::Hash(doc)["tags"].as_hash().map(Hash::len), Some(0));
+    }
+
+    #[test]
+    fn replaces_unquoted_account_ids() {
+        let mut doc =
+            parse("aliases:\n  accounts:\n    111122223333: old\ntags:\n  111122223333: [prod]\n");
+        set_alias(&mut doc, AliasKind::Accounts, "111122223333", Some("new")).unwrap();
+        set_tags(&mut doc, "111122223333", &["staging".into()]).unwrap();
+        let text = dump(&doc);
+        assert_eq!(text.matches("111122223333").count(), 2, "{}", text);
+        let yaml = Yaml::Hash(doc.clone());
+        assert_eq!(
+            yaml["aliases"]["accounts"]["111122223333"].as_str(),
+            Some("new")
+        );
+        assert_eq!(yaml["tags"]["111122223333"][0].as_str(), Some("staging"));
+
+        let mut doc =
+            parse("aliases:\n  accounts:\n    111122223333: old\ntags:\n  111122223333: [prod]\n");
+        set_alias(&mut doc, AliasKind::Accounts, "111122223333", None).unwrap();
+        set_tags(&mut doc, "111122223333", &[]).unwrap();
+        assert!(!dump(&doc).contains("111122223333"));
+    }
+
+    #[test]
+    fn failed_edit_leaves_the_file_alone() {
+        let path = config_path("failed-edit");
+        let original = "partitions: []\ncredential_margin_minutes: 5\n";
+        fs::write(&path, original).unwrap();
+        let result = edit(&path, |doc| {
+            doc.insert(key("credential_margin_minutes"), key("soon"));
+            Ok(())
+        });
+        assert!(matches!(result, Err(ArsdError::Config(_))));
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+        assert!(!backup_path(&path).exists());
+        assert!(!path.with_extension("new.yaml").exists());
+    }
+}
@@ -1,14 +1,121 @@
 use regex::Regex;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use url::Url;
 
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Settings {
     pub path: PathBuf,
     pub partitions: Vec<Partition>,
     pub aliases: Aliases,
+    #[serde(default)]
+    pub credential_server: CredentialServer,
+    #[serde(default)]
+    pub imds: Imds,
+    /// cached role credentials are used until they are this close to expiring
+    #[serde(default = "default_credential_margin_minutes")]
+    pub credential_margin_minutes: i64,
+    /// keep the database encryption key in this file instead of the OS keyring
+    #[serde(default)]
+    pub secret_key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub role_preference: RolePreference,
+    /// tags like `prod` or `team-x`, keyed by account ID, email address or a `/regex/` matching the
+    /// account name
+    #[serde(default)]
+    pub tags: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub notifications: Notifications,
+    /// found by `validate`, partitions with errors have already been left out
+    #[serde(skip)]
+    pub problems: Vec<ConfigProblem>,
 }
+
+/// The settings managed as app state. `config.yaml` can change while arsd runs, so commands take
+/// a snapshot with `current` instead of holding a reference across awaits.
+pub struct SharedSettings {
+    settings: RwLock<Arc<Settings>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> Self {
+        SharedSettings {
+            modified: Mutex::new(modified_at(&settings.path)),
+            settings: RwLock::new(Arc::new(settings)),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Re-read the config file if its modification time changed since the last load. When the
+    /// new file doesn't parse, the error is returned and the last good settings stay in place.
+    /// A missing file is ignored rather than treated as an empty config, which editors that
+    /// replace files on save would otherwise trigger.
+    pub fn reload_if_changed(&self) -> Option<Result<Arc<Settings>, config::ConfigError>> {
+        let path = self.current().path.clone();
+        let modified = modified_at(&path)?;
+        if *self.modified.lock().unwrap() == Some(modified) {
+            return None;
+        }
+        Some(self.reload())
+    }
+
+    /// Re-read the config file now, e.g. after arsd wrote it. When it can't be read, the last good
+    /// settings are kept with the error as their only problem.
+    pub fn reload(&self) -> Result<Arc<Settings>, config::ConfigError> {
+        let path = self.current().path.clone();
+        *self.modified.lock().unwrap() = modified_at(&path);
+        match get_configuration(path) {
+            Ok(s) => {
+                let s = Arc::new(s);
+                *self.settings.write().unwrap() = s.clone();
+                Ok(s)
+            }
+            Err(e) => {
+                let mut kept = (*self.current()).clone();
+                kept.problems = vec![ConfigProblem::unreadable(&e)];
+                *self.settings.write().unwrap() = Arc::new(kept);
+                Err(e)
+            }
+        }
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn default_credential_margin_minutes() -> i64 {
+    10
+}
+
 impl Settings {
+    /// Settings with nothing configured, used until config.yaml exists or when it can't be read.
+    pub fn empty(path: PathBuf) -> Self {
+        Settings {
+            path,
+            partitions: vec![],
+            aliases: Aliases {
+                accounts: HashMap::new(),
+                roles: HashMap::new(),
+            },
+            credential_server: CredentialServer::default(),
+            imds: Imds::default(),
+            credential_margin_minutes: default_credential_margin_minutes(),
+            secret_key_file: None,
+            role_preference: RolePreference::default(),
+            tags: HashMap::new(),
+            notifications: Notifications::default(),
+            problems: vec![],
+        }
+    }
+
     pub fn partition(&self, p: String) -> Option<Partition> {
         for candidate in self.partitions.iter() {
             if candidate.slug() == p {
@@ -17,6 +124,157 @@ impl Settings {
         }
         None
     }
+
+    pub fn credential_margin(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.credential_margin_minutes)
+    }
+
+    /// Every tag with a key matching the account, sorted and without duplicates.
+    pub fn tags_for(&self, account_id: &str, email: &str, account_name: &str) -> Vec<String> {
+        let mut tags: Vec<String> = self
+            .tags
+            .iter()
+            .filter(|(key, _)| match slash_regex(key) {
+                Some(re) => re.is_match(account_name),
+                None => key.as_str() == account_id || email_matches(key, email),
+            })
+            .flat_map(|(_, t)| t.iter().cloned())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+}
+
+/// Compile `/expr/` patterns from the config. `None` for plain names and invalid expressions,
+/// which are logged.
+fn slash_regex(pattern: &str) -> Option<Regex> {
+    let expr = pattern.strip_prefix('/')?.strip_suffix('/')?;
+    match Regex::new(expr) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            log::warn!("Invalid regex {} in config: {}", pattern, e);
+            None
+        }
+    }
+}
+
+/// Local endpoint serving credentials in the ECS container format
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct CredentialServer {
+    #[serde(default)]
+    pub enabled: bool,
+    /// 0 picks a free port every time the app starts
+    #[serde(default)]
+    pub port: u16,
+}
+
+/// IMDSv2 emulator serving the role picked as active in the app
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct Imds {
+    #[serde(default)]
+    pub enabled: bool,
+    /// must be a loopback address
+    #[serde(default = "default_imds_address")]
+    pub address: Ipv4Addr,
+    #[serde(default)]
+    pub port: u16,
+}
+
+fn default_imds_address() -> Ipv4Addr {
+    Ipv4Addr::LOCALHOST
+}
+
+impl Default for Imds {
+    fn default() -> Self {
+        Imds {
+            enabled: false,
+            address: default_imds_address(),
+            port: 0,
+        }
+    }
+}
+
+/// Desktop notifications before a session or copied credentials expire
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct Notifications {
+    #[serde(default = "default_notifications_enabled")]
+    pub enabled: bool,
+    /// minutes before an SSO token expires, only for tokens that can't be refreshed
+    #[serde(default = "default_token_minutes")]
+    pub token_minutes: Vec<i64>,
+    /// minutes before credentials copied from arsd expire
+    #[serde(default = "default_credential_minutes")]
+    pub credential_minutes: Vec<i64>,
+}
+
+fn default_notifications_enabled() -> bool {
+    true
+}
+
+fn default_token_minutes() -> Vec<i64> {
+    vec![30, 5]
+}
+
+fn default_credential_minutes() -> Vec<i64> {
+    vec![5]
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Notifications {
+            enabled: default_notifications_enabled(),
+            token_minutes: default_token_minutes(),
+            credential_minutes: default_credential_minutes(),
+        }
+    }
+}
+
+/// Roles to use, most preferred first, when an account is opened without picking a role. Entries
+/// are role names or regexes wrapped in slashes, like `/.*ReadOnly.*/`.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct RolePreference {
+    #[serde(default)]
+    pub default: Vec<String>,
+    /// overrides keyed by account ID or email address
+    #[serde(default)]
+    pub accounts: HashMap<String, Vec<String>>,
+    /// overrides for accounts with a tag, used when there isn't one for the account itself
+    #[serde(default)]
+    pub tags: HashMap<String, Vec<String>>,
+}
+
+impl RolePreference {
+    fn order_for(&self, account_id: &str, email: Option<&str>, tags: &[String]) -> &[String] {
+        self.accounts
+            .get(account_id)
+            .or_else(|| email.and_then(|e| get_by_email(&self.accounts, e)))
+            .or_else(|| tags.iter().find_map(|t| self.tags.get(t)))
+            .unwrap_or(&self.default)
+    }
+
+    /// Pick the most preferred of `roles`, or the first by name if none of them are listed.
+    pub fn pick<'a>(
+        &self,
+        account_id: &str,
+        email: Option<&str>,
+        tags: &[String],
+        roles: &'a [String],
+    ) -> Option<&'a String> {
+        for pattern in self.order_for(account_id, email, tags) {
+            if let Some(r) = roles.iter().find(|r| role_matches(pattern, r)) {
+                return Some(r);
+            }
+        }
+        roles.iter().min()
+    }
+}
+
+fn role_matches(pattern: &str, role_name: &str) -> bool {
+    match slash_regex(pattern) {
+        Some(re) => re.is_match(role_name),
+        None => pattern.eq_ignore_ascii_case(role_name),
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
@@ -25,14 +283,98 @@ pub struct Aliases {
     roles: HashMap<String, String>,
 }
 impl Aliases {
+    /// Alias for a role, from an exact role name key or else the first `/regex/` key that matches.
     pub fn map_role(&self, role_name: String) -> String {
         match self.roles.get(&role_name) {
             Some(a) => a.to_owned(),
-            None => role_name,
+            None => alias_from_patterns(&self.roles, &[&role_name]).unwrap_or(role_name),
+        }
+    }
+
+    /// Alias for an account. Keys are tried in a fixed order: account ID, email address, account
+    /// name, then `/regex/` keys in sorted order against the account name and then the email.
+    pub fn map_account(&self, account_id: &str, account_name: &str, email: &str) -> Option<String> {
+        self.accounts
+            .get(account_id)
+            .or_else(|| get_by_email(&self.accounts, email))
+            .or_else(|| self.accounts.get(account_name))
+            .cloned()
+            .or_else(|| alias_from_patterns(&self.accounts, &[account_name, email]))
+    }
+}
+
+/// Email keys match regardless of ASCII case, the same way in aliases, tags and role preferences.
+fn email_matches(key: &str, email: &str) -> bool {
+    key.eq_ignore_ascii_case(email)
+}
+
+/// The value for `email`, preferring a key with exactly the same case.
+fn get_by_email<'a, V>(map: &'a HashMap<String, V>, email: &str) -> Option<&'a V> {
+    map.get(email).or_else(|| {
+        let mut keys: Vec<&String> = map.keys().filter(|k| email_matches(k, email)).collect();
+        keys.sort();
+        keys.first().and_then(|k| map.get(*k))
+    })
+}
+
+/// Expand the alias of the first `/regex/` key (in sorted order) matching one of `subjects`, so
+/// `$1` or `${name}` in the alias are replaced by capture groups.
+fn alias_from_patterns(aliases: &HashMap<String, String>, subjects: &[&str]) -> Option<String> {
+    let mut patterns: Vec<(&String, &String)> = aliases.iter().collect();
+    patterns.sort();
+    for (pattern, alias) in patterns {
+        if let Some(re) = slash_regex(pattern) {
+            for subject in subjects {
+                if let Some(caps) = re.captures(subject) {
+                    let mut expanded = String::new();
+                    caps.expand(alias, &mut expanded);
+                    return Some(expanded);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// The AWS partition an Identity Center instance lives in, which decides the sign-in and console
+/// domains used for federation.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AwsPartition {
+    Aws,
+    AwsCn,
+    AwsUsGov,
+}
+impl AwsPartition {
+    pub fn from_region(region: &str) -> Self {
+        if region.starts_with("cn-") {
+            AwsPartition::AwsCn
+        } else if region.starts_with("us-gov-") {
+            AwsPartition::AwsUsGov
+        } else {
+            AwsPartition::Aws
+        }
+    }
+
+    pub fn console_domain(&self) -> &'static str {
+        match self {
+            AwsPartition::Aws => "aws.amazon.com",
+            AwsPartition::AwsCn => "amazonaws.cn",
+            AwsPartition::AwsUsGov => "amazonaws-us-gov.com",
         }
     }
-    pub fn map_account(&self, account_email: String) -> Option<String> {
-        self.accounts.get(&account_email).cloned()
+
+    pub fn federation_url(&self) -> String {
+        format!("https://signin.{}/federation", self.console_domain())
+    }
+
+    /// Only the commercial partition has regional console hostnames, the others pick the region
+    /// from the query string.
+    pub fn console_host(&self, region: &str) -> String {
+        match self {
+            AwsPartition::Aws => format!("{}.console.{}", region, self.console_domain()),
+            _ => format!("console.{}", self.console_domain()),
+        }
     }
 }
 
@@ -41,6 +383,15 @@ pub struct Partition {
     pub start_url: String,
     pub account_id: Option<String>,
     pub region: String,
+    /// Region the web console opens in, defaults to `region`
+    #[serde(default)]
+    pub console_region: Option<String>,
+    /// Per-account overrides of `console_region`, keyed by account ID
+    #[serde(default)]
+    pub account_console_regions: HashMap<String, String>,
+    /// Only needed when it can't be told from `region`
+    #[serde(default, rename = "aws_partition")]
+    pub aws_partition_override: Option<AwsPartition>,
 }
 impl Partition {
     pub fn scopes(&self) -> Vec<String> {
@@ -54,21 +405,248 @@ impl Partition {
             .await
     }
 
+    pub fn console_region_for(&self, account_id: &str) -> String {
+        match self.account_console_regions.get(account_id) {
+            Some(r) => r.clone(),
+            None => self
+                .console_region
+                .clone()
+                .unwrap_or_else(|| self.region.clone()),
+        }
+    }
+
+    pub fn aws_partition(&self) -> AwsPartition {
+        self.aws_partition_override
+            .unwrap_or_else(|| AwsPartition::from_region(&self.region))
+    }
+
+    /// The part of the start URL that identifies the Identity Center instance:
+    /// - `https://d-123abc.awsapps.com/start#` and `https://d-123abc.awsapps.cn/start#` give the
+    ///   subdomain
+    /// - GovCloud `https://start.us-gov-home.awsapps.com/directory/d-123abc` gives the directory
+    /// - vanity domains such as `https://sso.example.com/start` give the whole host
+    fn start_url_id(&self) -> Option<String> {
+        let url = Url::parse(&self.start_url).ok()?;
+        if url.scheme() != "https" {
+            return None;
+        }
+        let host = url.host_str()?;
+        let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+        if segments.next() == Some("directory") {
+            return segments.next().map(|d| d.to_string());
+        }
+        match host
+            .strip_suffix(".awsapps.com")
+            .or_else(|| host.strip_suffix(".awsapps.cn"))
+        {
+            Some(sub) if !sub.contains('.') => Some(sub.to_string()),
+            Some(_) => None,
+            None => Some(host.replace('.', "-")),
+        }
+    }
+
+    /// `<region>-<start url id>`. Partitions with a start URL `start_url_id` can't read are dropped
+    /// by `validate`, the fallback only keeps the slug unique if one is built by hand.
     pub fn slug(&self) -> String {
-        let re = Regex::new(r"^https://(.+)\.awsapps\.com/start#$").unwrap();
-        let caps = re.captures(&self.start_url).unwrap();
-        format!("{}-{}", self.region, caps.get(1).unwrap().as_str())
+        let id = self.start_url_id().unwrap_or_else(|| {
+            self.start_url
+                .replace(|c: char| !c.is_ascii_alphanumeric(), "-")
+                .trim_matches('-')
+                .to_string()
+        });
+        format!("{}-{}", self.region, id)
     }
     pub fn sso_start_url(&self) -> String {
-        let re = Regex::new(r"^https://.+\.awsapps\.com/start#$").unwrap();
-        if re.is_match(self.start_url.as_str()) {
-            self.start_url.clone()
-        } else {
-            panic!("Invalid start_url: {}", self.start_url);
+        self.start_url.clone()
+    }
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// the setting is ignored
+    Error,
+    /// the setting is used, but probably isn't what was meant
+    Warning,
+}
+
+/// Something wrong in config.yaml, shown in the app instead of stopping it.
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    /// where in the file, like `partitions[0].start_url`, empty for the whole file
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    fn error(path: String, message: String) -> Self {
+        ConfigProblem {
+            severity: Severity::Error,
+            path,
+            message,
+        }
+    }
+
+    fn warning(path: String, message: String) -> Self {
+        ConfigProblem {
+            severity: Severity::Warning,
+            path,
+            message,
         }
     }
+
+    /// The file couldn't be read or parsed at all.
+    pub fn unreadable(e: &config::ConfigError) -> Self {
+        ConfigProblem::error(String::new(), e.to_string())
+    }
+}
+
+fn is_account_id(s: &str) -> bool {
+    s.len() == 12 && s.chars().all(|c| c.is_ascii_digit())
+}
+
+fn is_known_region(region: &str) -> bool {
+    Regex::new(
+        r"^[a-z]{2}(-gov|-iso[bef]?)?-(north|south|east|west|central|northeast|northwest|southeast|southwest)-\d+$",
+    )
+    .unwrap()
+    .is_match(region)
 }
 
+/// Check `settings` and record what's wrong in `settings.problems`. Partitions that would break
+/// signing in (unreadable start URLs, a second partition with the same slug) are removed.
+fn validate(mut settings: Settings) -> Settings {
+    let mut problems = vec![];
+
+    let mut slugs = HashSet::new();
+    for (i, p) in std::mem::take(&mut settings.partitions)
+        .into_iter()
+        .enumerate()
+    {
+        let at = |field: &str| format!("partitions[{}].{}", i, field);
+        if p.start_url_id().is_none() {
+            problems.push(ConfigProblem::error(
+                at("start_url"),
+                format!(
+                    "{} is not an Identity Center start URL like https://d-123abc.awsapps.com/start#",
+                    p.start_url
+                ),
+            ));
+            continue;
+        }
+        if !slugs.insert(p.slug()) {
+            problems.push(ConfigProblem::error(
+                at("start_url"),
+                format!(
+                    "{} in {} is configured more than once, only the first is used",
+                    p.start_url, p.region
+                ),
+            ));
+            continue;
+        }
+        for (field, region) in std::iter::once(("region", &p.region))
+            .chain(p.console_region.iter().map(|r| ("console_region", r)))
+        {
+            if !is_known_region(region) {
+                problems.push(ConfigProblem::warning(
+                    at(field),
+                    format!("{} is not a known AWS region", region),
+                ));
+            }
+        }
+        if let Some(id) = p.account_id.as_ref().filter(|id| !is_account_id(id)) {
+            problems.push(ConfigProblem::warning(
+                at("account_id"),
+                format!("{} is not a 12 digit account ID", id),
+            ));
+        }
+        let mut overrides: Vec<(&String, &String)> = p.account_console_regions.iter().collect();
+        overrides.sort();
+        for (account, region) in overrides {
+            if !is_account_id(account) {
+                problems.push(ConfigProblem::warning(
+                    at("account_console_regions"),
+                    format!("{} is not a 12 digit account ID", account),
+                ));
+            }
+            if !is_known_region(region) {
+                problems.push(ConfigProblem::warning(
+                    at("account_console_regions"),
+                    format!("{} is not a known AWS region", region),
+                ));
+            }
+        }
+        settings.partitions.push(p);
+    }
+
+    for (path, keys) in [
+        ("aliases.accounts", key_list(&settings.aliases.accounts)),
+        ("aliases.roles", key_list(&settings.aliases.roles)),
+        ("tags", key_list(&settings.tags)),
+        (
+            "role_preference.accounts",
+            key_list(&settings.role_preference.accounts),
+        ),
+    ] {
+        for key in keys {
+            if let Some(expr) = key.strip_prefix('/').and_then(|k| k.strip_suffix('/')) {
+                if let Err(e) = Regex::new(expr) {
+                    problems.push(ConfigProblem::warning(
+                        format!("{}.{}", path, key),
+                        format!("Invalid regex: {}", e),
+                    ));
+                }
+            } else if key.chars().all(|c| c.is_ascii_digit()) && !is_account_id(&key) {
+                problems.push(ConfigProblem::warning(
+                    format!("{}.{}", path, key),
+                    format!("{} looks like an account ID but isn't 12 digits", key),
+                ));
+            }
+        }
+    }
+
+    for (path, aliases) in [
+        ("aliases.accounts", &settings.aliases.accounts),
+        ("aliases.roles", &settings.aliases.roles),
+    ] {
+        let mut by_alias: HashMap<&String, Vec<&String>> = HashMap::new();
+        for (key, alias) in aliases {
+            // regex aliases with capture groups are different for every match
+            if !alias.contains('$') {
+                by_alias.entry(alias).or_default().push(key);
+            }
+        }
+        let mut collisions: Vec<(&String, Vec<&String>)> =
+            by_alias.into_iter().filter(|(_, k)| k.len() > 1).collect();
+        collisions.sort();
+        for (alias, mut keys) in collisions {
+            keys.sort();
+            problems.push(ConfigProblem::warning(
+                path.to_string(),
+                format!(
+                    "{} share the alias {}",
+                    keys.iter()
+                        .map(|k| k.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    alias
+                ),
+            ));
+        }
+    }
+
+    settings.problems = problems;
+    settings
+}
+
+fn key_list<V>(map: &HashMap<String, V>) -> Vec<String> {
+    let mut keys: Vec<String> = map.keys().cloned().collect();
+    keys.sort();
+    keys
+}
+
+/// Read config.yaml, which is then checked by `validate`. Only a file that can't be parsed at all
+/// is an error.
 pub fn get_configuration(file: PathBuf) -> Result<Settings, config::ConfigError> {
     let mut partial = config::Config::builder();
     partial = partial.add_source(config::File::from_str(
@@ -83,14 +661,8 @@ pub fn get_configuration(file: PathBuf) -> Result<Settings, config::ConfigError>
     if file.is_file() {
         partial = partial.add_source(config::File::from(file.clone()));
     } else {
-        return Ok(Settings {
-            path: file,
-            partitions: vec![],
-            aliases: Aliases {
-                accounts: HashMap::new(),
-                roles: HashMap::new(),
-            },
-        });
+        log::info!("No config file at {:?}, starting without partitions", file);
+        return Ok(Settings::empty(file));
     }
     let settings = partial
         .set_override("path", file.to_string_lossy().to_string())?
@@ -102,12 +674,5 @@ pub fn get_configuration(file: PathBuf) -> Result<Settings, config::ConfigError>
         )
         .build()?;
 
-    let parsed = settings.try_deserialize::<Settings>()?;
-    if parsed.partitions.len() > 1 {
-        panic!(
-            "Sorry, only one partition at a time is supported right now due to UI limitations. \
-            Upvote https://github.com/ryansb/arsd/issues/2 if you need more than one partition."
-        );
-    }
-    Ok(parsed)
+    settings.try_deserialize::<Settings>().map(validate)
 }
@@ -1,3 +1,4 @@
 pub mod storage;
 pub use storage::AccountInfo;
+pub use storage::RegistrationInfo;
 pub use storage::RoleInfo;
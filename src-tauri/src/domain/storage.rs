@@ -1,5 +1,10 @@
+use chrono::{DateTime, Utc};
+use std::path::PathBuf;
 use whoami;
 
+/// Must match `identifier` in tauri.conf.json5 so tools outside the app find the same files.
+pub(crate) const APP_IDENTIFIER: &str = "io.rsb.arsd";
+
 pub fn client_name() -> String {
     format!(
         "{} arsd {}@{}",
@@ -9,13 +14,25 @@ pub fn client_name() -> String {
     )
 }
 
+/// Same location as tauri's `app_config_dir`, for binaries that don't have an `AppHandle`.
+pub fn app_config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join(APP_IDENTIFIER))
+}
+
+/// Same location as tauri's `app_data_dir`, for binaries that don't have an `AppHandle`.
+pub fn app_data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|d| d.join(APP_IDENTIFIER))
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct AccountInfo {
+    pub partition: String,
     pub account_id: String,
     pub account_name: String,
     pub email_address: String,
     pub alias: Option<String>,
-    pub score: Option<i64>,
+    pub score: Option<f64>,
+    pub tags: Vec<String>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -24,4 +41,16 @@ pub struct RoleInfo {
     pub role_name: String,
     pub partition: String,
     pub alias: Option<String>,
+    pub score: Option<f64>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub use_count: i64,
+}
+
+/// When a partition's OIDC client registration was issued and when it expires, without the
+/// client secret.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RegistrationInfo {
+    pub partition: String,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
 }
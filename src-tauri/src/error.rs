@@ -0,0 +1,91 @@
+use aws_sdk_sso::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use std::fmt;
+
+/// Errors returned by commands and `session` functions. Serialized for the frontend as
+/// `{"kind": "NoToken", "message": "..."}`, matching `ArsdError` in errors.ts.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", content = "message")]
+pub enum ArsdError {
+    UnknownPartition(String),
+    NoToken(String),
+    TokenExpired(String),
+    SsoThrottled(String),
+    SsoAccessDenied(String),
+    Sso(String),
+    Database(String),
+    Network(String),
+    Io(String),
+    Secret(String),
+    Config(String),
+}
+
+impl fmt::Display for ArsdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArsdError::UnknownPartition(p) => write!(f, "No partition found for {}", p),
+            ArsdError::NoToken(p) => write!(f, "Not signed in to {}", p),
+            ArsdError::TokenExpired(m) => write!(f, "SSO session expired: {}", m),
+            ArsdError::SsoThrottled(m) => write!(f, "SSO is throttling requests: {}", m),
+            ArsdError::SsoAccessDenied(m) => write!(f, "SSO denied access: {}", m),
+            ArsdError::Sso(m) => write!(f, "SSO error: {}", m),
+            ArsdError::Database(m) => write!(f, "Local database error: {}", m),
+            ArsdError::Network(m) => write!(f, "Network error: {}", m),
+            ArsdError::Io(m) => write!(f, "File error: {}", m),
+            ArsdError::Secret(m) => write!(f, "Secret storage error: {}", m),
+            ArsdError::Config(m) => write!(f, "Configuration error: {}", m),
+        }
+    }
+}
+
+impl std::error::Error for ArsdError {}
+
+impl From<rusqlite::Error> for ArsdError {
+    fn from(e: rusqlite::Error) -> Self {
+        ArsdError::Database(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for ArsdError {
+    fn from(e: reqwest::Error) -> Self {
+        ArsdError::Network(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for ArsdError {
+    fn from(e: std::io::Error) -> Self {
+        ArsdError::Io(e.to_string())
+    }
+}
+
+impl From<aws_sdk_sso::Error> for ArsdError {
+    fn from(e: aws_sdk_sso::Error) -> Self {
+        let message = DisplayErrorContext(&e).to_string();
+        // not modeled by the SDK, but returned when the user has no access to the role
+        let denied = matches!(
+            e.code(),
+            Some("ForbiddenException" | "AccessDeniedException")
+        );
+        match e {
+            aws_sdk_sso::Error::TooManyRequestsException(_) => ArsdError::SsoThrottled(message),
+            aws_sdk_sso::Error::UnauthorizedException(_) => ArsdError::TokenExpired(message),
+            _ if denied => ArsdError::SsoAccessDenied(message),
+            _ => ArsdError::Sso(message),
+        }
+    }
+}
+
+impl<E, R> From<SdkError<E, R>> for ArsdError
+where
+    E: std::error::Error + 'static,
+    R: fmt::Debug,
+    aws_sdk_sso::Error: From<SdkError<E, R>>,
+{
+    fn from(e: SdkError<E, R>) -> Self {
+        match e {
+            SdkError::DispatchFailure(_) | SdkError::TimeoutError(_) => {
+                ArsdError::Network(DisplayErrorContext(&e).to_string())
+            }
+            _ => aws_sdk_sso::Error::from(e).into(),
+        }
+    }
+}
@@ -1,4 +1,9 @@
+pub mod config_file;
 pub mod configuration;
 pub mod domain;
+pub mod error;
+pub mod profiles;
+pub mod secrets;
+pub mod server;
 pub mod session;
 pub mod sql;
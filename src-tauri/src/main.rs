@@ -1,15 +1,20 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use arsd::session::login::ConfirmationInfo;
+use std::collections::HashMap;
 use tauri::Listener;
 use tauri::{AppHandle, Emitter, EventTarget, Manager, State};
 use tauri_plugin_log::Target as LogTarget;
 use tauri_plugin_log::TargetKind as LogTargetKind;
 
-use arsd::configuration::{get_configuration, Settings};
-use arsd::domain::{AccountInfo, RoleInfo};
-use arsd::session::{account, account::Credentials, console_url, events, login};
+use arsd::config_file;
+use arsd::configuration::{get_configuration, ConfigProblem, Settings, SharedSettings};
+use arsd::domain::{AccountInfo, RegistrationInfo, RoleInfo};
+use arsd::error::ArsdError;
+use arsd::profiles;
+use arsd::secrets::{self, SecretStore};
+use arsd::server;
+use arsd::session::{account, account::Credentials, console_url, events, expiry, login};
 use arsd::sql;
 use arsd::sql::ServiceAccess;
 
@@ -26,7 +31,7 @@ struct AppDirs {
     logs: String,
 }
 #[tauri::command]
-fn storage_path(app: tauri::AppHandle, settings: State<Settings>) -> AppDirs {
+fn storage_path(app: tauri::AppHandle, settings: State<SharedSettings>) -> AppDirs {
     AppDirs {
         data: app
             .path()
@@ -34,7 +39,7 @@ fn storage_path(app: tauri::AppHandle, settings: State<Settings>) -> AppDirs {
             .unwrap()
             .to_string_lossy()
             .to_string(),
-        config: settings.path.to_string_lossy().to_string(),
+        config: settings.current().path.to_string_lossy().to_string(),
         logs: app
             .path()
             .app_log_dir()
@@ -44,55 +49,67 @@ fn storage_path(app: tauri::AppHandle, settings: State<Settings>) -> AppDirs {
     }
 }
 
+#[tauri::command]
+async fn storage_registrations(app: tauri::AppHandle) -> Result<Vec<RegistrationInfo>, ArsdError> {
+    Ok(app.db(|db| sql::models::Registration::list(db))?)
+}
+
 #[tauri::command]
 async fn open_web_console(
-    config: State<'_, Settings>,
+    settings: State<'_, SharedSettings>,
     app: tauri::AppHandle,
     role_name: String,
     partition: String,
     account_id: String,
-) -> Result<String, String> {
+    region: Option<String>,
+    service: Option<String>,
+) -> Result<String, ArsdError> {
+    let config = settings.current();
+    let part = config
+        .partition(partition.clone())
+        .ok_or_else(|| ArsdError::UnknownPartition(partition.clone()))?;
     app.db_mut(|db| {
         sql::models::HistoryNew {
             partition: partition.clone(),
             account: account_id.clone(),
             role: role_name.clone(),
             style: sql::models::AssumeStyle::WebConsole,
-            service: None,
+            service: service.clone(),
         }
         .insert(db)
-    })
-    .unwrap();
-    match config.partition(partition) {
-        None => todo!("Bad partition"),
-        Some(part) => Ok(console_url::get_console_url(account_id, role_name, part, app).await),
-    }
+    })?;
+    console_url::get_console_url(account_id, role_name, part, app, region, service).await
 }
 
 #[tauri::command]
 async fn list_accounts(
-    config: State<'_, Settings>,
+    settings: State<'_, SharedSettings>,
     app: tauri::AppHandle,
     partition: String,
-) -> Result<Vec<AccountInfo>, String> {
-    match config.partition(partition.clone()) {
-        None => todo!("Bad partition"),
-        Some(part) => {
-            return Ok(account::list_accounts(part, app.clone())
-                .await
-                .iter()
-                .map(|a| AccountInfo {
-                    account_id: a.account_id.clone(),
-                    account_name: a.account_name.clone(),
-                    email_address: a.email_address.clone(),
-                    alias: config.aliases.map_account(a.email_address.clone()),
-                    score: app.db(|db| {
-                        sql::models::Account::score(db, partition.clone(), a.account_id.clone())
-                    }),
-                })
-                .collect());
-        }
-    }
+    tag: Option<String>,
+) -> Result<Vec<AccountInfo>, ArsdError> {
+    let config = settings.current();
+    let part = config
+        .partition(partition.clone())
+        .ok_or_else(|| ArsdError::UnknownPartition(partition.clone()))?;
+    let half_life = app.db(sql::models::SettingHalfLife::get)?;
+    let scores = app.db(|db| sql::models::Account::scores(db, partition.clone(), half_life))?;
+    Ok(account::list_accounts(part, app.clone())
+        .await?
+        .iter()
+        .map(|a| AccountInfo {
+            tags: config.tags_for(&a.account_id, &a.email_address, &a.account_name),
+            partition: partition.clone(),
+            account_id: a.account_id.clone(),
+            account_name: a.account_name.clone(),
+            email_address: a.email_address.clone(),
+            alias: config
+                .aliases
+                .map_account(&a.account_id, &a.account_name, &a.email_address),
+            score: scores.get(&a.account_id).copied(),
+        })
+        .filter(|a| tag.as_ref().map_or(true, |t| a.tags.contains(t)))
+        .collect())
 }
 
 #[tauri::command]
@@ -101,7 +118,7 @@ async fn settings_get_sort(app: tauri::AppHandle) -> i32 {
 }
 
 #[tauri::command]
-async fn settings_save_sort(sort: i32, app: tauri::AppHandle) -> Result<(), String> {
+async fn settings_save_sort(sort: i32, app: tauri::AppHandle) -> Result<(), ArsdError> {
     let extant = app.db_mut(|db| match sort {
         0 => sql::models::SettingSort {
             value: sql::models::SortOrder::ALPHA,
@@ -116,14 +133,38 @@ async fn settings_save_sort(sort: i32, app: tauri::AppHandle) -> Result<(), Stri
     match extant {
         Ok(_) => Ok(()),
         Err(e) => {
-            log::error!("Failed to delete local data: {:?}", e);
-            Err(String::from("Delete failed"))
+            log::error!("Failed to save sort order: {:?}", e);
+            Err(e.into())
+        }
+    }
+}
+
+#[tauri::command]
+async fn settings_get_half_life(app: tauri::AppHandle) -> f64 {
+    app.db(sql::models::SettingHalfLife::get)
+        .unwrap_or(sql::models::SettingHalfLife::DEFAULT_DAYS)
+}
+
+#[tauri::command]
+async fn settings_save_half_life(days: f64, app: tauri::AppHandle) -> Result<(), ArsdError> {
+    if !(days.is_finite() && days > 0.0) {
+        return Err(ArsdError::Config(format!(
+            "The frecency half-life must be a positive number of days, got {}",
+            days
+        )));
+    }
+    let extant = app.db(|db| sql::models::SettingHalfLife { value: days }.insert(db));
+    match extant {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            log::error!("Failed to save frecency half-life: {:?}", e);
+            Err(e.into())
         }
     }
 }
 
 #[tauri::command]
-async fn delete_cache(app: tauri::AppHandle) -> Result<(), String> {
+async fn delete_cache(app: tauri::AppHandle) -> Result<(), ArsdError> {
     let extant = app.db(|db| {
         db.execute_batch(
             "BEGIN;
@@ -131,6 +172,7 @@ async fn delete_cache(app: tauri::AppHandle) -> Result<(), String> {
             DELETE FROM registrations WHERE true;
             DELETE FROM accounts WHERE true;
             DELETE FROM tokens WHERE true;
+            DELETE FROM role_credentials WHERE true;
             COMMIT;",
         )
     });
@@ -138,76 +180,146 @@ async fn delete_cache(app: tauri::AppHandle) -> Result<(), String> {
         Ok(_) => Ok(()),
         Err(e) => {
             log::error!("Failed to delete local data: {:?}", e);
-            Err(String::from("Delete failed"))
+            Err(e.into())
         }
     }
 }
 
+/// Sign out of one partition. The registration is kept unless asked for, so signing in again
+/// skips registering the device. Role credentials cached in the database are deleted unless asked
+/// to keep them, and the endpoints always drop the ones they hold in memory.
+#[tauri::command]
+async fn logout(
+    app: AppHandle,
+    settings: State<'_, SharedSettings>,
+    partition: String,
+    forget_registration: Option<bool>,
+    clear_credentials: Option<bool>,
+) -> Result<(), ArsdError> {
+    let config = settings.current();
+    let part = config
+        .partition(partition.clone())
+        .ok_or_else(|| ArsdError::UnknownPartition(partition.clone()))?;
+    app.state::<login::DevicePollers>().stop(&partition);
+    app.state::<login::SessionManager>()
+        .session(&app, &part)
+        .await
+        .lock()
+        .await
+        .next(login::Event::Logout)
+        .await;
+    if forget_registration.unwrap_or(false) {
+        app.db(|db| sql::models::Registration::delete(db, partition.clone()))?;
+    }
+    app.state::<std::sync::Arc<server::CredentialCache>>()
+        .clear_partition(&partition);
+    if clear_credentials.unwrap_or(true) {
+        app.db(|db| sql::models::RoleCredentials::delete(db, Some(partition.clone())))?;
+    }
+    log::info!("Signed out of {}", partition);
+    Ok(())
+}
+
 #[tauri::command]
 async fn list_roles_for(
-    config: State<'_, Settings>,
+    settings: State<'_, SharedSettings>,
     app: tauri::AppHandle,
     partition: String,
     account_id: String,
-) -> Result<Vec<RoleInfo>, String> {
-    match config.partition(partition) {
-        None => todo!("Bad partition"),
-        Some(part) => {
-            let extant = app
-                .db(|db| sql::models::Role::list(db, part.slug(), account_id.clone()))
-                .unwrap()
-                .iter()
-                .map(|r| RoleInfo {
-                    role_name: r.role_name.clone(),
-                    alias: Some(config.aliases.map_role(r.role_name.clone())),
-                    account_id: r.account_id.clone(),
-                    partition: r.partition.clone(),
-                })
-                .collect::<Vec<RoleInfo>>();
-            if !extant.is_empty() {
-                log::debug!(
-                    "Found roles for {} in db: {:?}",
-                    account_id.clone(),
-                    extant.len()
-                );
-                return Ok(extant);
-            }
-            let token = match app
-                .db(|db| sql::models::Token::find(db, part.slug()))
-                .unwrap()
-            {
-                None => {
-                    log::warn!("No token found for {}", part.slug());
-                    return Ok(vec![]);
-                }
-                Some(t) => t,
-            };
-            let roles =
-                account::list_roles(part.clone(), token.access_token, account_id.clone()).await;
-            for r in roles.iter() {
-                log::warn!("Inserting role: {:?}", r.clone());
-                app.db_mut(|db| {
-                    sql::models::Role {
-                        partition: part.slug(),
-                        account_id: account_id.clone(),
-                        role_name: r.role_name.clone(),
-                        updated_at: chrono::Utc::now(),
-                    }
-                    .insert(db)
-                })
-                .unwrap();
+) -> Result<Vec<RoleInfo>, ArsdError> {
+    let config = settings.current();
+    let part = config
+        .partition(partition.clone())
+        .ok_or_else(|| ArsdError::UnknownPartition(partition.clone()))?;
+    let half_life = app.db(sql::models::SettingHalfLife::get)?;
+    let usages =
+        app.db(|db| sql::models::Role::usages(db, part.slug(), account_id.clone(), half_life))?;
+    let extant: Vec<RoleInfo> = app
+        .db(|db| sql::models::Role::list(db, part.slug(), account_id.clone()))?
+        .into_iter()
+        .map(|r| role_with_usage(&config, &usages, r.as_info()))
+        .collect();
+    if !extant.is_empty() {
+        log::debug!(
+            "Found roles for {} in db: {:?}",
+            account_id.clone(),
+            extant.len()
+        );
+        return Ok(extant);
+    }
+    let token = match app.db(|db| sql::models::Token::find(db, app.secrets(), part.slug()))? {
+        None => {
+            log::warn!("No token found for {}", part.slug());
+            return Err(ArsdError::NoToken(part.slug()));
+        }
+        Some(t) => t,
+    };
+    let roles = account::list_roles(part.clone(), token.access_token, account_id.clone()).await?;
+    for r in roles.iter() {
+        log::warn!("Inserting role: {:?}", r.clone());
+        app.db_mut(|db| {
+            sql::models::Role {
+                partition: part.slug(),
+                account_id: account_id.clone(),
+                role_name: r.role_name.clone(),
+                updated_at: chrono::Utc::now(),
             }
+            .insert(db)
+        })?;
+    }
 
-            return Ok(roles
-                .iter()
-                .map(|r| RoleInfo {
-                    role_name: r.role_name.clone(),
-                    alias: Some(config.aliases.map_role(r.role_name.clone())),
-                    account_id: r.account_id.clone(),
-                    partition: r.partition.clone(),
-                })
-                .collect());
-        }
+    Ok(roles
+        .into_iter()
+        .map(|r| role_with_usage(&config, &usages, r))
+        .collect())
+}
+
+/// The role to use when the account is opened without picking one, from `role_preference`
+#[tauri::command]
+async fn default_role_for(
+    settings: State<'_, SharedSettings>,
+    app: tauri::AppHandle,
+    partition: String,
+    account_id: String,
+) -> Result<Option<RoleInfo>, ArsdError> {
+    let roles = list_roles_for(
+        settings.clone(),
+        app.clone(),
+        partition.clone(),
+        account_id.clone(),
+    )
+    .await?;
+    let config = settings.current();
+    let account =
+        app.db(|db| sql::models::Account::find(db, partition.clone(), account_id.clone()))?;
+    let email = account.as_ref().map(|a| a.email_address.as_str());
+    let tags = account
+        .as_ref()
+        .map(|a| config.tags_for(&account_id, &a.email_address, &a.account_name))
+        .unwrap_or_default();
+    let names: Vec<String> = roles.iter().map(|r| r.role_name.clone()).collect();
+    let picked = config
+        .role_preference
+        .pick(&account_id, email, &tags, &names)
+        .cloned();
+    Ok(roles
+        .into_iter()
+        .find(|r| Some(&r.role_name) == picked.as_ref()))
+}
+
+/// Fill in the alias and the frecency, last use and use count from `Role::usages`.
+fn role_with_usage(
+    config: &Settings,
+    usages: &HashMap<String, sql::models::RoleUsage>,
+    role: RoleInfo,
+) -> RoleInfo {
+    let usage = usages.get(&role.role_name);
+    RoleInfo {
+        alias: Some(config.aliases.map_role(role.role_name.clone())),
+        score: usage.and_then(|u| u.score),
+        last_used_at: usage.and_then(|u| u.last_used_at),
+        use_count: usage.map_or(0, |u| u.use_count),
+        ..role
     }
 }
 
@@ -220,7 +332,8 @@ struct PartitionDisplay {
 }
 
 #[tauri::command]
-fn get_partitions(config: State<Settings>) -> Vec<PartitionDisplay> {
+fn get_partitions(settings: State<SharedSettings>) -> Vec<PartitionDisplay> {
+    let config = settings.current();
     config
         .partitions
         .iter()
@@ -233,14 +346,115 @@ fn get_partitions(config: State<Settings>) -> Vec<PartitionDisplay> {
         .collect::<Vec<PartitionDisplay>>()
 }
 
+/// Write a change to config.yaml and load it right away instead of waiting for the file watcher.
+fn edit_config(
+    app: &AppHandle,
+    settings: &SharedSettings,
+    edit: impl FnOnce(&mut config_file::Hash) -> Result<(), ArsdError>,
+) -> Result<(), ArsdError> {
+    config_file::edit(&settings.current().path, edit)?;
+    settings
+        .reload()
+        .map_err(|e| ArsdError::Config(e.to_string()))?;
+    app.emit_to(EventTarget::any(), "config_reloaded", ())
+        .unwrap();
+    Ok(())
+}
+
+#[tauri::command]
+fn config_problems(settings: State<SharedSettings>) -> Vec<ConfigProblem> {
+    settings.current().problems.clone()
+}
+
+/// Whether saving from the app would drop comments from config.yaml
+#[tauri::command]
+fn config_has_comments(settings: State<SharedSettings>) -> bool {
+    config_file::has_comments(&settings.current().path)
+}
+
+/// Read config.yaml now instead of waiting for the file watcher
+#[tauri::command]
+fn config_reload(app: AppHandle, settings: State<SharedSettings>) -> Result<(), ArsdError> {
+    let reloaded = settings.reload();
+    app.emit_to(EventTarget::any(), "config_reloaded", ())
+        .unwrap();
+    reloaded
+        .map(|_| ())
+        .map_err(|e| ArsdError::Config(e.to_string()))
+}
+
+/// Add an Identity Center instance, creating config.yaml on first run
+#[tauri::command]
+fn config_add_partition(
+    start_url: String,
+    region: String,
+    account_id: Option<String>,
+    app: AppHandle,
+    settings: State<SharedSettings>,
+) -> Result<(), ArsdError> {
+    edit_config(&app, &settings, |doc| {
+        config_file::add_partition(doc, &start_url, &region, account_id.as_deref())
+    })
+}
+
+#[tauri::command]
+fn config_remove_partition(
+    partition: String,
+    app: AppHandle,
+    settings: State<SharedSettings>,
+) -> Result<(), ArsdError> {
+    let part = settings
+        .current()
+        .partition(partition.clone())
+        .ok_or_else(|| ArsdError::UnknownPartition(partition.clone()))?;
+    edit_config(&app, &settings, |doc| {
+        config_file::remove_partition(doc, &part.start_url, &part.region)
+    })
+}
+
+/// Set or clear (with `None`) an alias. `name` is any key `Aliases` accepts
+#[tauri::command]
+fn config_set_alias(
+    kind: config_file::AliasKind,
+    name: String,
+    alias: Option<String>,
+    app: AppHandle,
+    settings: State<SharedSettings>,
+) -> Result<(), ArsdError> {
+    edit_config(&app, &settings, |doc| {
+        config_file::set_alias(doc, kind, &name, alias.as_deref())
+    })
+}
+
+#[tauri::command]
+fn config_set_tags(
+    name: String,
+    tags: Vec<String>,
+    app: AppHandle,
+    settings: State<SharedSettings>,
+) -> Result<(), ArsdError> {
+    let tags: Vec<String> = tags
+        .iter()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect();
+    edit_config(&app, &settings, |doc| {
+        config_file::set_tags(doc, &name, &tags)
+    })
+}
+
 #[tauri::command]
 async fn get_credentials_for(
     partition: String,
     account_id: String,
     role_name: String,
     app: AppHandle,
-    config: State<'_, Settings>,
-) -> Result<Credentials, String> {
+    settings: State<'_, SharedSettings>,
+) -> Result<Credentials, ArsdError> {
+    let config = settings.current();
+    let part = config
+        .partition(partition.clone())
+        .ok_or_else(|| ArsdError::UnknownPartition(partition.clone()))?;
     app.db_mut(|db| {
         sql::models::HistoryNew {
             partition: partition.clone(),
@@ -250,104 +464,219 @@ async fn get_credentials_for(
             service: None,
         }
         .insert(db)
-    })
-    .unwrap();
-    Ok(account::get_credentials(
-        config.partition(partition).unwrap(),
-        role_name,
+    })?;
+    let creds =
+        account::get_credentials(part, role_name.clone(), account_id.clone(), app.clone()).await?;
+    app.state::<expiry::ExpiryScheduler>().vended(
+        partition,
         account_id,
-        app,
-    )
-    .await)
+        role_name,
+        creds.expires_at,
+    );
+    Ok(creds)
 }
 
+/// Port and token of the container credentials endpoint, if it's enabled
 #[tauri::command]
-async fn check_device_token(
-    token_event: ConfirmationInfo,
+fn container_server(app: AppHandle) -> Option<server::container::ContainerServer> {
+    app.try_state::<server::container::ContainerServer>()
+        .map(|s| s.inner().clone())
+}
+
+/// Credentials endpoint for one role, for `AWS_CONTAINER_CREDENTIALS_FULL_URI`
+#[tauri::command]
+fn container_credentials_uri(
     app: AppHandle,
-    config: State<'_, Settings>,
-) -> Result<String, String> {
-    match config.partition(token_event.partition.clone()) {
-        None => Err(format!("No partition found for {}", token_event.partition)),
-        Some(partition) => {
-            log::info!(
-                "Checking device token for partition: {:?}",
-                partition.sso_start_url()
-            );
-            let mut sess = login::SessionState::new(app.clone(), partition.clone())
-                .await
-                .unwrap();
-            match sess
-                .next(login::Event::ConfirmDeviceAuthorization(token_event))
-                .await
-            {
-                login::State::AwaitingConfirmation(c) => {
-                    log::info!("check_device_token still awaiting confirmation: {:?}", c);
-                    Ok(String::from("Pending"))
-                }
-                login::State::Ready => {
-                    app.emit_to(
-                        EventTarget::any(),
-                        "token_ready",
-                        events::AuthorizeDevice {
-                            partition_name: partition.slug(),
-                        },
-                    )
-                    .unwrap();
-                    Ok(String::from("Done"))
-                }
-                _ => todo!("Handle other states"),
-            }
+    partition: String,
+    account_id: String,
+    role_name: String,
+) -> Option<String> {
+    app.try_state::<server::container::ContainerServer>()
+        .map(|s| s.credentials_uri(&partition, &account_id, &role_name))
+}
+
+#[derive(serde::Serialize)]
+struct ImdsInfo {
+    endpoint: String,
+    active_role: Option<server::imds::ActiveRole>,
+}
+
+/// Endpoint and active role of the IMDS emulator, if it's enabled
+#[tauri::command]
+fn imds_server(app: AppHandle) -> Option<ImdsInfo> {
+    app.try_state::<server::imds::ImdsServer>()
+        .map(|s| ImdsInfo {
+            endpoint: s.endpoint.clone(),
+            active_role: s.active_role(),
+        })
+}
+
+/// Pick the role served by the IMDS emulator, or clear it with `None`
+#[tauri::command]
+fn imds_set_active_role(
+    role: Option<server::imds::ActiveRole>,
+    app: AppHandle,
+    settings: State<SharedSettings>,
+) -> Result<(), ArsdError> {
+    let config = settings.current();
+    let imds = match app.try_state::<server::imds::ImdsServer>() {
+        Some(s) => s,
+        None => {
+            return Err(ArsdError::Io(String::from(
+                "The IMDS emulator is not enabled",
+            )))
         }
+    };
+    if let Some(r) = role.as_ref() {
+        config
+            .partition(r.partition.clone())
+            .ok_or_else(|| ArsdError::UnknownPartition(r.partition.clone()))?;
     }
+    imds.set_active_role(role.clone());
+    app.emit_to(EventTarget::any(), "imds_active_role", role)
+        .unwrap();
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct AwsConfigExport {
+    path: String,
+    diff: String,
+    written: bool,
+}
+
+#[tauri::command]
+fn export_aws_config(
+    settings: State<SharedSettings>,
+    app: AppHandle,
+    style: profiles::ProfileStyle,
+    write: bool,
+) -> Result<AwsConfigExport, ArsdError> {
+    let config = settings.current();
+    let path = match profiles::aws_config_path() {
+        Some(p) => p,
+        None => {
+            return Err(ArsdError::Io(String::from(
+                "Could not find the AWS config file location",
+            )))
+        }
+    };
+    let existing = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            log::error!("Failed to read {:?}: {:?}", path, e);
+            return Err(e.into());
+        }
+    };
+    let block = app.db(|db| {
+        profiles::render(
+            &config,
+            db,
+            style,
+            profiles::credential_process_path().as_str(),
+        )
+    })?;
+    let updated = profiles::merge(&existing, &block);
+    if write {
+        if let Err(e) = profiles::write(&path, &updated) {
+            log::error!("Failed to write {:?}: {:?}", path, e);
+            return Err(e.into());
+        }
+    }
+    Ok(AwsConfigExport {
+        path: path.to_string_lossy().to_string(),
+        diff: profiles::diff(&existing, &updated),
+        written: write,
+    })
 }
 
 #[tauri::command]
 async fn authorize_device(
     auth_event: events::AuthorizeDevice,
+    renew: Option<bool>,
     app: AppHandle,
-    config: State<'_, Settings>,
-) -> Result<login::DeviceAuthState, String> {
-    match config.partition(auth_event.partition_name.clone()) {
-        None => Err(format!(
-            "No partition found for {}",
-            auth_event.partition_name
-        )),
-        Some(partition) => {
-            log::debug!("Found partition: {:?}", partition.sso_start_url());
-            let mut sess = login::SessionState::new(app.clone(), partition.clone())
-                .await
-                .unwrap();
-            let mut event: login::Event = login::Event::RegisterDevice;
-            loop {
-                let st = sess.next(event.clone()).await;
-                log::info!(
-                    "Stepped state event: {:?} and machine: {:?}",
-                    event.clone(),
-                    st
-                );
-                match st {
-                    login::State::Ready => {
-                        log::info!("Token is ready");
-                        let token = app
-                            .db(|db| sql::models::Token::find(db, partition.slug()).unwrap())
-                            .unwrap();
-                        return Ok(login::DeviceAuthState::Success(login::SuccessInfo {
-                            expires_at: token.expires_at,
-                        }));
-                    }
-                    login::State::Registered => {
-                        log::info!("Device is registered");
-                        event = login::Event::StartDeviceAuthorization;
-                    }
-                    login::State::AwaitingConfirmation(c) => {
-                        log::info!("Needs confirmation: {:?}", c);
-                        return Ok(login::DeviceAuthState::NeedsConfirmation(c));
+    settings: State<'_, SharedSettings>,
+) -> Result<login::DeviceAuthState, ArsdError> {
+    let config = settings.current();
+    let partition = config
+        .partition(auth_event.partition_name.clone())
+        .ok_or_else(|| ArsdError::UnknownPartition(auth_event.partition_name.clone()))?;
+    log::debug!("Found partition: {:?}", partition.sso_start_url());
+    if let Some(c) = app
+        .state::<login::DevicePollers>()
+        .pending(&partition.slug())
+    {
+        log::info!("Still polling for confirmation: {:?}", c);
+        return Ok(login::DeviceAuthState::NeedsConfirmation(c));
+    }
+    let session = app
+        .state::<login::SessionManager>()
+        .session(&app, &partition)
+        .await;
+    let mut sess = session.lock().await;
+    // a token that's still good for a while is used as is, the background refresh renews it
+    let refresh_due = app
+        .db(|db| sql::models::Token::find_refreshable(db, app.secrets(), partition.slug()))?
+        .is_some_and(|t| login::refresh_due(&t));
+    let mut event: login::Event = if renew.unwrap_or(false) {
+        // sign in again before the current token runs out
+        login::Event::Renew
+    } else if refresh_due {
+        login::Event::RefreshToken
+    } else {
+        login::Event::RegisterDevice
+    };
+    // a rejected registration is forgotten and sign-in starts over, but only once
+    let mut restarted = false;
+    loop {
+        let st = sess.next(event.clone()).await;
+        log::info!(
+            "Stepped state event: {:?} and machine: {:?}",
+            event.clone(),
+            st
+        );
+        match st {
+            login::State::Ready => {
+                log::info!("Token is ready");
+                let token = app
+                    .db(|db| sql::models::Token::find(db, app.secrets(), partition.slug()))?
+                    .ok_or_else(|| ArsdError::NoToken(partition.slug()))?;
+                return Ok(login::DeviceAuthState::Success(login::SuccessInfo {
+                    expires_at: token.expires_at,
+                }));
+            }
+            login::State::Start => {
+                if matches!(event, login::Event::StartDeviceAuthorization) {
+                    if restarted {
+                        return Err(ArsdError::Sso(String::from(
+                            "SSO rejected the new device registration, try again later",
+                        )));
                     }
-                    login::State::Failed { message } => return Err(message),
-                    _ => todo!("Handle other states"),
+                    restarted = true;
                 }
+                log::info!("Token could not be refreshed, registering device");
+                event = login::Event::RegisterDevice;
+            }
+            login::State::Registered => {
+                log::info!("Device is registered");
+                event = login::Event::StartDeviceAuthorization;
+            }
+            login::State::AwaitingConfirmation(c) => {
+                log::info!("Needs confirmation: {:?}", c);
+                app.state::<login::DevicePollers>().start(
+                    app.clone(),
+                    partition.clone(),
+                    c.clone(),
+                );
+                return Ok(login::DeviceAuthState::NeedsConfirmation(c));
             }
+            login::State::Expired => {
+                return Err(ArsdError::Sso(String::from(
+                    "The confirmation code expired before it was approved",
+                )))
+            }
+            login::State::Failed { message } => return Err(ArsdError::Sso(message)),
         }
     }
 }
@@ -404,20 +733,30 @@ fn main() {
                     }
                 }
             }
-            let config =
-                match get_configuration(app.path().app_config_dir().unwrap().join("config.yaml")) {
-                    Ok(c) => c,
-                    Err(e) => {
-                        log::error!(
-                            "Error loading configuration from {:?}",
-                            app.path().app_config_dir().unwrap().join("config.yaml")
-                        );
-                        log::error!("Failed to load configuration: {:?}", e);
-                        app.handle().exit(-1);
-                        return Ok(());
-                    }
-                };
-            app.manage(config.clone());
+            let config_file = app.path().app_config_dir().unwrap().join("config.yaml");
+            // start without partitions and list the problems in the app, the file watcher picks
+            // up the fix
+            let config = match get_configuration(config_file.clone()) {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!(
+                        "Failed to load configuration from {:?}: {:?}",
+                        config_file,
+                        e
+                    );
+                    let mut empty = Settings::empty(config_file);
+                    empty.problems = vec![ConfigProblem::unreadable(&e)];
+                    empty
+                }
+            };
+            for problem in config.problems.iter() {
+                log::warn!(
+                    "Configuration problem at {}: {}",
+                    problem.path,
+                    problem.message
+                );
+            }
+            app.manage(SharedSettings::new(config.clone()));
 
             app.listen_any("authorize_device", |event| {
                 log::info!("Received `authorize_device` event: {:?}", event);
@@ -436,15 +775,119 @@ fn main() {
             let sql_state = sql::connect::SqlRepo {
                 conn: std::sync::Mutex::new(None),
             };
-            *sql_state.conn.lock().unwrap() = Some(
-                sql::database::initialize_database(
-                    app.path()
-                        .app_data_dir()
-                        .expect("data dir must exist for us to make the DB"),
-                )
-                .expect("Database should initialize"),
-            );
+            let db = match sql::database::initialize_database(
+                app.path()
+                    .app_data_dir()
+                    .expect("data dir must exist for us to make the DB"),
+            ) {
+                Ok(db) => db,
+                Err(e) => {
+                    log::error!("Failed to open the database: {}", e);
+                    app.handle().exit(-1);
+                    return Ok(());
+                }
+            };
+            *sql_state.conn.lock().unwrap() = Some(db);
             app.manage(sql_state);
+            app.manage(login::SessionManager::default());
+            app.manage(login::DevicePollers::default());
+            app.manage(expiry::ExpiryScheduler::default());
+
+            let secret_store =
+                match SecretStore::open(&config, &app.path().app_config_dir().unwrap()) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log::error!("Failed to load the database encryption key: {}", e);
+                        app.handle().exit(-1);
+                        return Ok(());
+                    }
+                };
+            app.manage(secret_store);
+            // expiry checks read tokens, which needs the secret store
+            tauri::async_runtime::spawn(expiry::run(app.handle().clone()));
+            let handle = app.handle();
+            match handle.db_mut(|db| secrets::seal_plaintext_rows(db, handle.secrets())) {
+                Ok(0) => {}
+                Ok(n) => log::info!("Encrypted {} secrets stored by an older version", n),
+                Err(e) => log::error!("Failed to encrypt stored secrets: {:?}", e),
+            }
+
+            let credential_cache = std::sync::Arc::new(server::CredentialCache::default());
+            app.manage(credential_cache.clone());
+            if config.credential_server.enabled {
+                let handle = app.handle().clone();
+                let port = config.credential_server.port;
+                let cache = credential_cache.clone();
+                tauri::async_runtime::spawn(async move {
+                    match server::container::start(handle.clone(), port, cache).await {
+                        Ok(s) => {
+                            handle.manage(s);
+                        }
+                        Err(e) => {
+                            log::error!("Failed to start container credentials server: {:?}", e)
+                        }
+                    }
+                });
+            }
+            if config.imds.enabled {
+                let handle = app.handle().clone();
+                let (address, port) = (config.imds.address, config.imds.port);
+                let cache = credential_cache.clone();
+                tauri::async_runtime::spawn(async move {
+                    match server::imds::start(handle.clone(), address, port, cache).await {
+                        Ok(s) => {
+                            handle.manage(s);
+                        }
+                        Err(e) => log::error!("Failed to start IMDS emulator: {:?}", e),
+                    }
+                });
+            }
+
+            let refresher = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    let partitions = refresher
+                        .state::<SharedSettings>()
+                        .current()
+                        .partitions
+                        .clone();
+                    for partition in partitions {
+                        login::refresh_if_expiring(refresher.clone(), partition).await;
+                    }
+                    refresher
+                        .state::<login::SessionManager>()
+                        .expire_stale()
+                        .await;
+                    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                }
+            });
+
+            // server settings and `secret_key_file` are only read at startup, everything else
+            // comes from the reloaded settings on the next command
+            let watcher = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+                    match watcher.state::<SharedSettings>().reload_if_changed() {
+                        None => {}
+                        Some(Ok(settings)) => {
+                            log::info!("Reloaded configuration from {:?}", settings.path);
+                            watcher
+                                .emit_to(EventTarget::any(), "config_reloaded", ())
+                                .unwrap();
+                        }
+                        Some(Err(e)) => {
+                            log::error!(
+                                "Failed to reload configuration, keeping the last good one: {:?}",
+                                e
+                            );
+                            watcher
+                                .emit_to(EventTarget::any(), "config_error", e.to_string())
+                                .unwrap();
+                        }
+                    }
+                }
+            });
 
             tauri::async_runtime::spawn(async move {
                 main_window.show().unwrap();
@@ -474,16 +917,32 @@ fn main() {
         })
         .invoke_handler(tauri::generate_handler![
             authorize_device,
-            check_device_token,
+            config_add_partition,
+            config_has_comments,
+            config_problems,
+            config_reload,
+            config_remove_partition,
+            config_set_alias,
+            config_set_tags,
+            container_credentials_uri,
+            container_server,
+            default_role_for,
             delete_cache,
+            export_aws_config,
             get_credentials_for,
             get_partitions,
+            imds_server,
+            imds_set_active_role,
             list_accounts,
             list_roles_for,
+            logout,
             open_web_console,
+            settings_get_half_life,
             settings_get_sort,
+            settings_save_half_life,
             settings_save_sort,
             storage_path,
+            storage_registrations,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
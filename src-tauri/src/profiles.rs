@@ -0,0 +1,305 @@
+use rusqlite::Connection;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::configuration::{Partition, Settings};
+use crate::error::ArsdError;
+use crate::sql;
+
+const BEGIN_MARKER: &str =
+    "# BEGIN arsd managed profiles, changes inside this block are overwritten";
+const END_MARKER: &str = "# END arsd managed profiles";
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileStyle {
+    SsoSession,
+    CredentialProcess,
+}
+
+/// `AWS_CONFIG_FILE` if it is set, otherwise `~/.aws/config`.
+pub fn aws_config_path() -> Option<PathBuf> {
+    match std::env::var_os("AWS_CONFIG_FILE") {
+        Some(p) => Some(PathBuf::from(p)),
+        None => dirs::home_dir().map(|h| h.join(".aws").join("config")),
+    }
+}
+
+/// The `arsd-credential-process` binary installed next to the running app, or the bare name so
+/// the CLI looks it up on `PATH`.
+pub fn credential_process_path() -> String {
+    let name = format!("arsd-credential-process{}", std::env::consts::EXE_SUFFIX);
+    match std::env::current_exe() {
+        Ok(exe) => match exe.parent().map(|d| d.join(&name)) {
+            Some(p) if p.is_file() => p.to_string_lossy().to_string(),
+            _ => name,
+        },
+        Err(_) => name,
+    }
+}
+
+fn profile_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// `helper` as the program of a `credential_process` line. SDKs split the line like a shell, or
+/// hand it to one, so anything but plain path characters is double quoted, and characters a shell
+/// still expands inside double quotes are refused.
+fn quote_program(helper: &str) -> Result<String, ArsdError> {
+    if let Some(c) = helper
+        .chars()
+        .find(|c| matches!(c, '"' | '$' | '`' | '%' | '\n' | '\r'))
+    {
+        return Err(ArsdError::Io(format!(
+            "{} can't be used for credential_process because it contains {:?}",
+            helper, c
+        )));
+    }
+    if helper
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/._-:+=,@".contains(c))
+    {
+        Ok(helper.to_string())
+    } else {
+        Ok(format!("\"{}\"", helper))
+    }
+}
+
+fn sso_session_name(partition: &Partition) -> String {
+    format!("arsd-{}", partition.slug())
+}
+
+/// Render every known account/role pair as `[profile ...]` sections, wrapped in the arsd markers.
+pub fn render(
+    settings: &Settings,
+    db: &Connection,
+    style: ProfileStyle,
+    helper: &str,
+) -> Result<String, ArsdError> {
+    let program = quote_program(helper)?;
+    let mut lines: Vec<String> = vec![BEGIN_MARKER.to_string()];
+    let mut seen: HashSet<String> = HashSet::new();
+
+    for partition in settings.partitions.iter() {
+        if style == ProfileStyle::SsoSession {
+            lines.push(format!("[sso-session {}]", sso_session_name(partition)));
+            lines.push(format!(
+                "sso_start_url = {}",
+                partition.start_url.trim_end_matches('#')
+            ));
+            lines.push(format!("sso_region = {}", partition.region));
+            lines.push(format!(
+                "sso_registration_scopes = {}",
+                partition.scopes().join(",")
+            ));
+            lines.push(String::new());
+        }
+
+        let mut accounts = sql::models::Account::list(db, partition.slug())?;
+        accounts.sort_by(|a, b| a.account_name.cmp(&b.account_name));
+        for account in accounts {
+            let account_label = settings
+                .aliases
+                .map_account(
+                    &account.account_id,
+                    &account.account_name,
+                    &account.email_address,
+                )
+                .unwrap_or_else(|| account.account_name.clone());
+            let mut roles =
+                sql::models::Role::list(db, partition.slug(), account.account_id.clone())?;
+            roles.sort_by(|a, b| a.role_name.cmp(&b.role_name));
+            for role in roles {
+                let mut name = profile_safe(&format!(
+                    "{}-{}",
+                    account_label,
+                    settings.aliases.map_role(role.role_name.clone())
+                ));
+                if !seen.insert(name.clone()) {
+                    // same alias in two partitions or accounts, disambiguate with the account ID
+                    name = format!("{}-{}", name, account.account_id);
+                    seen.insert(name.clone());
+                }
+                lines.push(format!("[profile {}]", name));
+                match style {
+                    ProfileStyle::SsoSession => {
+                        lines.push(format!("sso_session = {}", sso_session_name(partition)));
+                        lines.push(format!("sso_account_id = {}", account.account_id));
+                        lines.push(format!("sso_role_name = {}", role.role_name));
+                    }
+                    ProfileStyle::CredentialProcess => {
+                        lines.push(format!(
+                            "credential_process = {} --partition {} --account {} --role {}",
+                            program,
+                            partition.slug(),
+                            account.account_id,
+                            role.role_name
+                        ));
+                    }
+                }
+                lines.push(format!("region = {}", partition.region));
+                lines.push(String::new());
+            }
+        }
+    }
+    if lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    lines.push(END_MARKER.to_string());
+    Ok(lines.join("\n"))
+}
+
+/// Replace the arsd-managed block in `existing`, or append it if there isn't one yet. Everything
+/// outside the markers is preserved as-is.
+pub fn merge(existing: &str, block: &str) -> String {
+    let begin = existing.find(BEGIN_MARKER);
+    let end = existing.find(END_MARKER);
+    match (begin, end) {
+        (Some(b), Some(e)) if b < e => format!(
+            "{}{}{}",
+            &existing[..b],
+            block,
+            &existing[e + END_MARKER.len()..]
+        ),
+        _ if existing.trim().is_empty() => format!("{}\n", block),
+        _ => format!("{}\n\n{}\n", existing.trim_end(), block),
+    }
+}
+
+/// Line diff of `old` to `new` with `-`/`+` markers, leaving out unchanged lines.
+pub fn diff(old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+
+    // longest common subsequence table, the files involved are small
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out: Vec<String> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out.push(format!("+{}", b[j]));
+            j += 1;
+        } else {
+            out.push(format!("-{}", a[i]));
+            i += 1;
+        }
+    }
+    out.join("\n")
+}
+
+pub fn write(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(profile: &str) -> String {
+        format!(
+            "{}\n[profile {}]\nregion = us-east-1\n{}",
+            BEGIN_MARKER, profile, END_MARKER
+        )
+    }
+
+    #[test]
+    fn merge_replaces_existing_block() {
+        let existing = format!("[default]\nregion = eu-west-1\n\n{}\n", block("old"));
+        assert_eq!(
+            merge(&existing, &block("new")),
+            format!("[default]\nregion = eu-west-1\n\n{}\n", block("new"))
+        );
+    }
+
+    #[test]
+    fn merge_appends_without_block() {
+        assert_eq!(
+            merge("[default]\nregion = eu-west-1\n", &block("new")),
+            format!("[default]\nregion = eu-west-1\n\n{}\n", block("new"))
+        );
+        assert_eq!(merge("", &block("new")), format!("{}\n", block("new")));
+    }
+
+    #[test]
+    fn merge_keeps_surrounding_content_byte_for_byte() {
+        let before = "# mine\r\n[default]\r\nregion = eu-west-1   \r\n\r\n\r\n";
+        let after = "\r\n[profile other]  \r\nregion=us-west-2";
+        let existing = format!("{}{}{}", before, block("old"), after);
+        let merged = merge(&existing, &block("new"));
+        assert!(merged.starts_with(before));
+        assert!(merged.ends_with(after));
+        assert_eq!(merged, format!("{}{}{}", before, block("new"), after));
+    }
+
+    #[test]
+    fn merge_is_stable() {
+        let existing = format!("[default]\nregion = eu-west-1\n\n{}\n", block("same"));
+        assert_eq!(merge(&existing, &block("same")), existing);
+    }
+
+    #[test]
+    fn diff_without_changes_is_empty() {
+        let existing = format!("[default]\n{}\n", block("same"));
+        assert_eq!(diff(&existing, &merge(&existing, &block("same"))), "");
+    }
+
+    #[test]
+    fn diff_shows_changed_lines() {
+        assert_eq!(
+            diff(&block("old"), &block("new")),
+            "+[profile new]\n-[profile old]"
+        );
+    }
+
+    #[test]
+    fn quotes_helper_paths() {
+        assert_eq!(
+            quote_program("/usr/local/bin/arsd-credential-process").unwrap(),
+            "/usr/local/bin/arsd-credential-process"
+        );
+        assert_eq!(
+            quote_program("/Applications/arsd app/arsd-credential-process").unwrap(),
+            "\"/Applications/arsd app/arsd-credential-process\""
+        );
+        assert_eq!(
+            quote_program("/opt/arsd (1)/arsd-credential-process;").unwrap(),
+            "\"/opt/arsd (1)/arsd-credential-process;\""
+        );
+        assert_eq!(
+            quote_program(r"C:\Program Files\arsd\arsd-credential-process.exe").unwrap(),
+            r#""C:\Program Files\arsd\arsd-credential-process.exe""#
+        );
+        for bad in [
+            "/tmp/$HOME/arsd",
+            "/tmp/`id`/arsd",
+            "/tmp/a\"b/arsd",
+            r"C:\%TEMP%\arsd",
+        ] {
+            assert!(quote_program(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! Encryption for the secrets arsd keeps in its database: SSO access and refresh tokens, OIDC
+//! client secrets and cached role credentials. Columns are sealed with AES-256-GCM under a key
+//! kept outside the database, in the OS keyring (Keychain, Secret Service, Credential Manager)
+//! or in a key file.
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rusqlite::{params, Connection};
+use std::path::{Path, PathBuf};
+
+use crate::configuration::Settings;
+use crate::domain::storage::APP_IDENTIFIER;
+use crate::error::ArsdError;
+
+/// Prefix of sealed values. Anything without it is a plaintext value from an older version.
+pub const SEALED_PREFIX: &str = "enc:v1:";
+
+const KEYRING_USER: &str = "database-key";
+const NONCE_BYTES: usize = 12;
+
+/// Where the database key lives.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySource {
+    Keyring,
+    File(PathBuf),
+}
+
+pub struct SecretStore {
+    cipher: Aes256Gcm,
+    pub source: KeySource,
+}
+
+/// Name a sealed column is bound to, so a value can't be copied to another row and still decrypt.
+pub fn secret_name(table: &str, keys: &[&str], column: &str) -> String {
+    format!("{}/{}/{}", table, keys.join("/"), column)
+}
+
+impl SecretStore {
+    pub fn new(key: &[u8], source: KeySource) -> Result<Self, ArsdError> {
+        if key.len() != 32 {
+            return Err(ArsdError::Secret(format!(
+                "Database key must be 32 bytes, found {}",
+                key.len()
+            )));
+        }
+        Ok(SecretStore {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+            source,
+        })
+    }
+
+    /// Use the key stored in the OS keyring, creating it on first use.
+    pub fn from_keyring() -> Result<Self, ArsdError> {
+        SecretStore::keyring_key(true)
+    }
+
+    fn keyring_key(create: bool) -> Result<Self, ArsdError> {
+        let entry = keyring::Entry::new(APP_IDENTIFIER, KEYRING_USER)
+            .map_err(|e| ArsdError::Secret(e.to_string()))?;
+        let encoded = match entry.get_password() {
+            Ok(k) => k,
+            Err(keyring::Error::NoEntry) if !create => {
+                return Err(ArsdError::Secret(String::from(
+                    "There is no database key in the OS keyring",
+                )))
+            }
+            Err(keyring::Error::NoEntry) => {
+                log::info!("Creating database key in the OS keyring");
+                let k = BASE64.encode(Aes256Gcm::generate_key(OsRng));
+                entry
+                    .set_password(&k)
+                    .map_err(|e| ArsdError::Secret(e.to_string()))?;
+                k
+            }
+            Err(e) => return Err(ArsdError::Secret(e.to_string())),
+        };
+        let key = BASE64
+            .decode(encoded.trim())
+            .map_err(|e| ArsdError::Secret(format!("Keyring database key is invalid: {}", e)))?;
+        SecretStore::new(&key, KeySource::Keyring)
+    }
+
+    /// Use the key in `path`, creating the file readable only by the current user if needed.
+    pub fn from_file(path: &Path) -> Result<Self, ArsdError> {
+        SecretStore::file_key(path, true)
+    }
+
+    fn file_key(path: &Path, create: bool) -> Result<Self, ArsdError> {
+        let encoded = match std::fs::read_to_string(path) {
+            Ok(k) => k,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !create => {
+                return Err(ArsdError::Secret(format!(
+                    "Database key file {:?} does not exist",
+                    path
+                )))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("Creating database key file {:?}", path);
+                let k = BASE64.encode(Aes256Gcm::generate_key(OsRng));
+                write_key_file(path, &k)?;
+                k
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let key = BASE64.decode(encoded.trim()).map_err(|e| {
+            ArsdError::Secret(format!("Database key file {:?} is invalid: {}", path, e))
+        })?;
+        SecretStore::new(&key, KeySource::File(path.to_path_buf()))
+    }
+
+    /// Use the configured `secret_key_file`, otherwise the OS keyring. When the keyring isn't
+    /// available (e.g. no Secret Service running) fall back to a key file in `fallback_dir`.
+    pub fn open(settings: &Settings, fallback_dir: &Path) -> Result<Self, ArsdError> {
+        if let Some(path) = settings.secret_key_file.as_ref() {
+            return SecretStore::from_file(path);
+        }
+        match SecretStore::from_keyring() {
+            Ok(s) => Ok(s),
+            Err(e) => {
+                log::warn!(
+                    "OS keyring unavailable, keeping the database key in {:?}: {}",
+                    fallback_dir,
+                    e
+                );
+                SecretStore::from_file(&fallback_dir.join("database.key"))
+            }
+        }
+    }
+
+    /// Like `open`, but only reads a key the app already created. Tools reading the database
+    /// next to the app use this, since a new key would leave them unable to read what it wrote.
+    pub fn open_existing(settings: &Settings, fallback_dir: &Path) -> Result<Self, ArsdError> {
+        if let Some(path) = settings.secret_key_file.as_ref() {
+            return SecretStore::file_key(path, false);
+        }
+        match SecretStore::keyring_key(false) {
+            Ok(s) => Ok(s),
+            // the app falls back to the key file when it can't reach the keyring either
+            Err(keyring_error) => SecretStore::file_key(&fallback_dir.join("database.key"), false)
+                .map_err(|e| ArsdError::Secret(format!("{}, and {}", keyring_error, e))),
+        }
+    }
+
+    pub fn seal(&self, name: &str, secret: &str) -> String {
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: secret.as_bytes(),
+                    aad: name.as_bytes(),
+                },
+            )
+            .expect("AES-GCM encryption only fails for oversized messages");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        format!("{}{}", SEALED_PREFIX, BASE64.encode(sealed))
+    }
+
+    /// Decrypt a value from `seal`. Plaintext values written by older versions are returned as-is.
+    pub fn unseal(&self, name: &str, stored: &str) -> Result<String, ArsdError> {
+        let encoded = match stored.strip_prefix(SEALED_PREFIX) {
+            Some(e) => e,
+            None => return Ok(stored.to_string()),
+        };
+        let raw = BASE64
+            .decode(encoded)
+            .map_err(|e| ArsdError::Secret(format!("{} is not valid base64: {}", name, e)))?;
+        if raw.len() < NONCE_BYTES {
+            return Err(ArsdError::Secret(format!("{} is truncated", name)));
+        }
+        let (nonce, ciphertext) = raw.split_at(NONCE_BYTES);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                ArsdError::Secret(format!(
+                    "{} could not be decrypted, the database key may have changed",
+                    name
+                ))
+            })?;
+        String::from_utf8(plaintext).map_err(|e| ArsdError::Secret(e.to_string()))
+    }
+}
+
+fn write_key_file(path: &Path, encoded: &str) -> Result<(), ArsdError> {
+    use std::io::Write;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(encoded.as_bytes())?;
+    Ok(())
+}
+
+/// Seal every secret column still holding plaintext from before encryption was added.
+pub fn seal_plaintext_rows(
+    db: &mut Connection,
+    secrets: &SecretStore,
+) -> Result<usize, rusqlite::Error> {
+    let tx = db.transaction()?;
+    let mut sealed = 0;
+    for (table, keys, column) in [
+        ("tokens", vec!["partition"], "access_token"),
+        ("tokens", vec!["partition"], "refresh_token"),
+        ("tokens", vec!["partition"], "client_secret"),
+        ("registrations", vec!["partition"], "client_secret"),
+        (
+            "role_credentials",
+            vec!["partition", "account_id", "role_name"],
+            "secret_access_key",
+        ),
+        (
+            "role_credentials",
+            vec!["partition", "account_id", "role_name"],
+            "session_token",
+        ),
+    ] {
+        let rows: Vec<(i64, Vec<String>, String)> = {
+            let mut statement = tx.prepare(&format!(
+                "SELECT rowid, {column}, {keys} FROM {table}
+                WHERE {column} IS NOT NULL AND {column} NOT LIKE '{SEALED_PREFIX}%'",
+                keys = keys.join(", "),
+            ))?;
+            let rows = statement.query_map([], |row| {
+                let key_values = (0..keys.len())
+                    .map(|i| row.get::<_, String>(i + 2))
+                    .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+                Ok((row.get(0)?, key_values, row.get(1)?))
+            })?;
+            rows.collect::<Result<_, _>>()?
+        };
+        for (rowid, key_values, plaintext) in rows {
+            let key_refs: Vec<&str> = key_values.iter().map(|k| k.as_str()).collect();
+            let name = secret_name(table, &key_refs, column);
+            tx.execute(
+                &format!("UPDATE {table} SET {column} = ?1 WHERE rowid = ?2"),
+                params![secrets.seal(&name, &plaintext), rowid],
+            )?;
+            sealed += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::database::upgrade_database_if_needed;
+
+    /// A store with a fresh key file, like `secret_key_file` would give.
+    fn file_store(test: &str) -> SecretStore {
+        let dir =
+            std::env::temp_dir().join(format!("arsd-secrets-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = SecretStore::from_file(&dir.join("database.key")).unwrap();
+        assert_eq!(store.source, KeySource::File(dir.join("database.key")));
+        store
+    }
+
+    #[test]
+    fn existing_key_file_is_not_created() {
+        let dir =
+            std::env::temp_dir().join(format!("arsd-secrets-existing-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("database.key");
+        assert!(matches!(
+            SecretStore::file_key(&path, false),
+            Err(ArsdError::Secret(_))
+        ));
+        assert!(!path.exists());
+
+        let name = secret_name("tokens", &["us-east-1-d-123"], "access_token");
+        let sealed = SecretStore::from_file(&path)
+            .unwrap()
+            .seal(&name, "hunter2");
+        let reopened = SecretStore::file_key(&path, false).unwrap();
+        assert_eq!(reopened.unseal(&name, &sealed).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn seal_round_trips() {
+        let store = file_store("round-trip");
+        let name = secret_name("tokens", &["us-east-1-d-123"], "access_token");
+        let sealed = store.seal(&name, "hunter2");
+        assert!(sealed.starts_with(SEALED_PREFIX));
+        assert!(!sealed.contains("hunter2"));
+        assert_eq!(store.unseal(&name, &sealed).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn key_file_is_reused() {
+        let store = file_store("reuse");
+        let name = secret_name("tokens", &["us-east-1-d-123"], "access_token");
+        let sealed = store.seal(&name, "hunter2");
+        let KeySource::File(path) = &store.source else {
+            panic!("expected a key file");
+        };
+        let reopened = SecretStore::from_file(path).unwrap();
+        assert_eq!(reopened.unseal(&name, &sealed).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn unseal_rejects_another_name() {
+        let store = file_store("wrong-name");
+        let sealed = store.seal(
+            &secret_name("tokens", &["us-east-1-d-123"], "access_token"),
+            "hunter2",
+        );
+        // same column in another partition's row, and another column in the same row
+        for name in [
+            secret_name("tokens", &["us-west-2-d-456"], "access_token"),
+            secret_name("tokens", &["us-east-1-d-123"], "refresh_token"),
+        ] {
+            assert!(matches!(
+                store.unseal(&name, &sealed),
+                Err(ArsdError::Secret(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn unseal_rejects_another_key() {
+        let name = secret_name("tokens", &["us-east-1-d-123"], "access_token");
+        let sealed = file_store("key-a").seal(&name, "hunter2");
+        assert!(matches!(
+            file_store("key-b").unseal(&name, &sealed),
+            Err(ArsdError::Secret(_))
+        ));
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let store = file_store("plaintext");
+        let name = secret_name("tokens", &["us-east-1-d-123"], "access_token");
+        assert_eq!(store.unseal(&name, "hunter2").unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn seals_plaintext_rows_once() {
+        let store = file_store("legacy-rows");
+        let mut db = Connection::open_in_memory().unwrap();
+        upgrade_database_if_needed(&mut db, 0).unwrap();
+        db.execute_batch(
+            "INSERT INTO tokens (partition, token_type, access_token, expires_at, refresh_token)
+                VALUES ('us-east-1-d-123', 'Bearer', 'access', '2030-01-01T00:00:00Z', 'refresh');
+            INSERT INTO tokens (partition, token_type, access_token, expires_at)
+                VALUES ('us-west-2-d-456', 'Bearer', 'other', '2030-01-01T00:00:00Z');
+            INSERT INTO registrations (partition, client_id, client_secret, expires_at, issued_at)
+                VALUES ('us-east-1-d-123', 'client', 'secret',
+                    '2030-01-01T00:00:00Z', '2024-01-01T00:00:00Z');",
+        )
+        .unwrap();
+
+        assert_eq!(seal_plaintext_rows(&mut db, &store).unwrap(), 4);
+        assert_eq!(seal_plaintext_rows(&mut db, &store).unwrap(), 0);
+
+        let (access, refresh): (String, Option<String>) = db
+            .query_row(
+                "SELECT access_token, refresh_token FROM tokens WHERE partition = 'us-east-1-d-123'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        let name = |column| secret_name("tokens", &["us-east-1-d-123"], column);
+        assert_eq!(
+            store.unseal(&name("access_token"), &access).unwrap(),
+            "access"
+        );
+        assert_eq!(
+            store
+                .unseal(&name("refresh_token"), &refresh.unwrap())
+                .unwrap(),
+            "refresh"
+        );
+        let missing: Option<String> = db
+            .query_row(
+                "SELECT refresh_token FROM tokens WHERE partition = 'us-west-2-d-456'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(missing, None);
+        let client_secret: String = db
+            .query_row("SELECT client_secret FROM registrations", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(
+            store
+                .unseal(
+                    &secret_name("registrations", &["us-east-1-d-123"], "client_secret"),
+                    &client_secret
+                )
+                .unwrap(),
+            "secret"
+        );
+    }
+}
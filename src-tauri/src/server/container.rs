@@ -0,0 +1,106 @@
+//! Serves credentials in the ECS container credentials format, so any SDK can use an arsd role with
+//! `AWS_CONTAINER_CREDENTIALS_FULL_URI` and `AWS_CONTAINER_AUTHORIZATION_TOKEN`.
+//!
+//! Each role has its own path: `/credentials/<partition>/<account id>/<role name>`
+use chrono::{DateTime, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tauri::AppHandle;
+use tokio::net::TcpListener;
+
+use super::{constant_time_eq, serve, CredentialCache, Request, Response};
+
+/// Connection details for the running server, managed as app state.
+#[derive(Clone, serde::Serialize)]
+pub struct ContainerServer {
+    pub port: u16,
+    pub authorization_token: String,
+}
+
+impl ContainerServer {
+    pub fn credentials_uri(&self, partition: &str, account_id: &str, role_name: &str) -> String {
+        format!(
+            "http://127.0.0.1:{}/credentials/{}/{}/{}",
+            self.port, partition, account_id, role_name
+        )
+    }
+}
+
+// https://docs.aws.amazon.com/sdkref/latest/guide/feature-container-credentials.html
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: DateTime<Utc>,
+}
+
+async fn handle(
+    request: Request,
+    app: AppHandle,
+    cache: Arc<CredentialCache>,
+    authorization_token: Arc<String>,
+) -> Response {
+    let authorized = request
+        .headers
+        .get("authorization")
+        .is_some_and(|t| constant_time_eq(t.as_bytes(), authorization_token.as_bytes()));
+    if !authorized {
+        return Response::text(401, "Missing or incorrect authorization token");
+    }
+    if request.method != "GET" {
+        return Response::text(405, "Only GET is supported");
+    }
+    let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+    let (partition, account_id, role_name) = match segments.as_slice() {
+        ["credentials", partition, account_id, role_name] => (*partition, *account_id, *role_name),
+        _ => return Response::text(404, "Expected /credentials/<partition>/<account>/<role>"),
+    };
+
+    match cache
+        .get(
+            &app,
+            partition.to_string(),
+            account_id.to_string(),
+            role_name.to_string(),
+        )
+        .await
+    {
+        Ok(creds) => Response::json(&ContainerCredentials {
+            access_key_id: creds.access_key_id,
+            secret_access_key: creds.secret_access_key,
+            token: creds.session_token,
+            expiration: creds.expires_at,
+        }),
+        Err(e) => {
+            log::warn!(
+                "Failed to serve container credentials for {}: {}",
+                request.path,
+                e
+            );
+            Response::from_error(&e)
+        }
+    }
+}
+
+/// Bind the server on loopback and serve it in the background. Port 0 picks a free port.
+pub async fn start(
+    app: AppHandle,
+    port: u16,
+    cache: Arc<CredentialCache>,
+) -> std::io::Result<ContainerServer> {
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port))).await?;
+    let server = ContainerServer {
+        port: listener.local_addr()?.port(),
+        authorization_token: Alphanumeric.sample_string(&mut rand::thread_rng(), 32),
+    };
+    log::info!("Serving container credentials on 127.0.0.1:{}", server.port);
+
+    let token = Arc::new(server.authorization_token.clone());
+    tauri::async_runtime::spawn(serve(listener, move |request| {
+        handle(request, app.clone(), cache.clone(), token.clone())
+    }));
+    Ok(server)
+}
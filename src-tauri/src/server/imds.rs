@@ -0,0 +1,239 @@
+//! Emulates the IMDSv2 endpoints the SDK instance profile providers use, serving whichever role
+//! was picked as "active" in the app. Point tools at it with `AWS_EC2_METADATA_SERVICE_ENDPOINT`.
+use chrono::{DateTime, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tauri::AppHandle;
+use tokio::net::TcpListener;
+
+use super::{serve, CredentialCache, Request, Response};
+
+const CREDENTIALS_PATH: &str = "/latest/meta-data/iam/security-credentials";
+const MAX_TOKEN_TTL_SECONDS: i64 = 21600;
+const LINK_LOCAL_HOST: &str = "169.254.169.254";
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ActiveRole {
+    pub partition: String,
+    pub account_id: String,
+    pub role_name: String,
+}
+
+/// Handle to the running emulator, managed as app state.
+#[derive(Clone)]
+pub struct ImdsServer {
+    pub endpoint: String,
+    address: SocketAddr,
+    active_role: Arc<Mutex<Option<ActiveRole>>>,
+    /// session tokens handed out by `PUT /latest/api/token` and when they expire
+    tokens: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+}
+
+impl ImdsServer {
+    pub fn active_role(&self) -> Option<ActiveRole> {
+        self.active_role.lock().unwrap().clone()
+    }
+
+    pub fn set_active_role(&self, role: Option<ActiveRole>) {
+        *self.active_role.lock().unwrap() = role;
+    }
+
+    fn issue_token(&self, ttl_seconds: i64) -> String {
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 48);
+        let mut tokens = self.tokens.lock().unwrap();
+        let now = Utc::now();
+        tokens.retain(|_, expires_at| *expires_at > now);
+        tokens.insert(token.clone(), now + chrono::Duration::seconds(ttl_seconds));
+        token
+    }
+
+    fn token_valid(&self, token: Option<&String>) -> bool {
+        match token {
+            Some(t) => self
+                .tokens
+                .lock()
+                .unwrap()
+                .get(t)
+                .is_some_and(|expires_at| *expires_at > Utc::now()),
+            None => false,
+        }
+    }
+}
+
+/// Whether the Host header names this server, so a page that rebinds its own DNS name to the
+/// loopback address can't talk to it from a browser.
+fn host_allowed(host: Option<&String>, address: SocketAddr) -> bool {
+    let host = match host {
+        Some(h) => h.to_lowercase(),
+        None => return false,
+    };
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port == address.port().to_string() => name.to_string(),
+        Some(_) => return false,
+        None => host,
+    };
+    name == address.ip().to_string() || name == "localhost" || name == LINK_LOCAL_HOST
+}
+
+// https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/instance-metadata-security-credentials.html
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct InstanceCredentials {
+    code: &'static str,
+    last_updated: DateTime<Utc>,
+    #[serde(rename = "Type")]
+    credential_type: &'static str,
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: DateTime<Utc>,
+}
+
+async fn handle(
+    request: Request,
+    app: AppHandle,
+    cache: Arc<CredentialCache>,
+    server: ImdsServer,
+) -> Response {
+    // like the real IMDS, refuse anything that came through a proxy
+    if request.headers.contains_key("x-forwarded-for") {
+        return Response::text(403, "Forbidden");
+    }
+    if !host_allowed(request.headers.get("host"), server.address) {
+        return Response::text(403, "Forbidden");
+    }
+    if request.method == "PUT" && request.path == "/latest/api/token" {
+        return match request
+            .headers
+            .get("x-aws-ec2-metadata-token-ttl-seconds")
+            .and_then(|ttl| ttl.parse::<i64>().ok())
+        {
+            Some(ttl) if (1..=MAX_TOKEN_TTL_SECONDS).contains(&ttl) => {
+                Response::text(200, server.issue_token(ttl).as_str())
+            }
+            _ => Response::text(400, "Invalid X-aws-ec2-metadata-token-ttl-seconds"),
+        };
+    }
+    if request.method != "GET" {
+        return Response::text(405, "Only GET is supported");
+    }
+    if !server.token_valid(request.headers.get("x-aws-ec2-metadata-token")) {
+        return Response::text(401, "Missing or expired X-aws-ec2-metadata-token");
+    }
+
+    let active = match server.active_role() {
+        Some(r) => r,
+        None => return Response::text(404, "No active role selected in arsd"),
+    };
+    let role_name = match request.path.strip_prefix(CREDENTIALS_PATH) {
+        Some("" | "/") => return Response::text(200, active.role_name.as_str()),
+        Some(rest) => rest.trim_start_matches('/'),
+        None => return Response::text(404, "Not Found"),
+    };
+    if role_name != active.role_name {
+        return Response::text(404, "Not Found");
+    }
+
+    match cache
+        .get(
+            &app,
+            active.partition.clone(),
+            active.account_id.clone(),
+            active.role_name.clone(),
+        )
+        .await
+    {
+        Ok(creds) => Response::json(&InstanceCredentials {
+            code: "Success",
+            last_updated: Utc::now(),
+            credential_type: "AWS-HMAC",
+            access_key_id: creds.access_key_id,
+            secret_access_key: creds.secret_access_key,
+            token: creds.session_token,
+            expiration: creds.expires_at,
+        }),
+        Err(e) => {
+            log::warn!(
+                "Failed to serve instance credentials for {:?}: {}",
+                active,
+                e
+            );
+            Response::from_error(&e)
+        }
+    }
+}
+
+/// Bind the emulator and serve it in the background. Only loopback addresses are accepted, so
+/// credentials never leave the machine.
+pub async fn start(
+    app: AppHandle,
+    address: Ipv4Addr,
+    port: u16,
+    cache: Arc<CredentialCache>,
+) -> std::io::Result<ImdsServer> {
+    if !address.is_loopback() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a loopback address", address),
+        ));
+    }
+    let listener = TcpListener::bind(SocketAddr::from((address, port))).await?;
+    let bound = listener.local_addr()?;
+    let server = ImdsServer {
+        endpoint: format!("http://{}", bound),
+        address: bound,
+        active_role: Arc::new(Mutex::new(None)),
+        tokens: Arc::new(Mutex::new(HashMap::new())),
+    };
+    log::info!("Serving instance metadata on {}", server.endpoint);
+
+    let handler_server = server.clone();
+    tauri::async_runtime::spawn(serve(listener, move |request| {
+        handle(request, app.clone(), cache.clone(), handler_server.clone())
+    }));
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(host: Option<&str>) -> bool {
+        host_allowed(
+            host.map(String::from).as_ref(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 1338)),
+        )
+    }
+
+    #[test]
+    fn accepts_hosts_naming_the_server() {
+        for host in [
+            "127.0.0.1",
+            "127.0.0.1:1338",
+            "localhost",
+            "LocalHost:1338",
+            "169.254.169.254",
+            "169.254.169.254:1338",
+        ] {
+            assert!(allowed(Some(host)), "{} should be allowed", host);
+        }
+    }
+
+    #[test]
+    fn rejects_other_hosts() {
+        for host in [
+            "attacker.example.com",
+            "attacker.example.com:1338",
+            "127.0.0.1.nip.io:1338",
+            "127.0.0.1:80",
+            "localhost:8080",
+            "127.0.0.2:1338",
+            "",
+        ] {
+            assert!(!allowed(Some(host)), "{} should be rejected", host);
+        }
+        assert!(!allowed(None));
+    }
+}
@@ -0,0 +1,203 @@
+//! Loopback HTTP endpoints that hand role credentials to SDKs. Only what the SDK credential
+//! providers send is supported: one request per connection, no request bodies.
+pub mod container;
+pub mod imds;
+
+use chrono::Utc;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::configuration::SharedSettings;
+use crate::error::ArsdError;
+use crate::session::account::{self, Credentials};
+
+const MAX_REQUEST_BYTES: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// keys are lowercased
+    pub headers: HashMap<String, String>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json<T: serde::Serialize>(body: &T) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/json",
+            body: serde_json::to_string(body).unwrap(),
+        }
+    }
+
+    pub fn text(status: u16, body: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn from_error(e: &ArsdError) -> Self {
+        let status = match e {
+            ArsdError::UnknownPartition(_) => 404,
+            ArsdError::NoToken(_) | ArsdError::TokenExpired(_) => 401,
+            ArsdError::SsoAccessDenied(_) => 403,
+            ArsdError::SsoThrottled(_) => 429,
+            _ => 500,
+        };
+        Response::text(status, e.to_string().as_str())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        _ => "Internal Server Error",
+    }
+}
+
+fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+        .collect();
+    Some(Request {
+        method,
+        path,
+        headers,
+    })
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut buf: Vec<u8> = vec![];
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return parse_request(std::str::from_utf8(&buf[..end]).ok()?);
+        }
+        if buf.len() > MAX_REQUEST_BYTES {
+            return None;
+        }
+    }
+}
+
+async fn handle_connection<F, Fut>(mut stream: TcpStream, handler: F)
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let request =
+        match tokio::time::timeout(std::time::Duration::from_secs(5), read_request(&mut stream))
+            .await
+        {
+            Ok(Some(r)) => r,
+            _ => return,
+        };
+    log::debug!("{} {}", request.method, request.path);
+    let response = handler(request).await;
+    let raw = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    if let Err(e) = stream.write_all(raw.as_bytes()).await {
+        log::warn!("Failed to write credential server response: {:?}", e);
+    }
+}
+
+/// Accept connections forever, answering each with `handler`.
+pub async fn serve<F, Fut>(listener: TcpListener, handler: F)
+where
+    F: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                tauri::async_runtime::spawn(handle_connection(stream, handler));
+            }
+            Err(e) => log::warn!("Failed to accept connection: {:?}", e),
+        }
+    }
+}
+
+type CacheKey = (String, String, String);
+type CacheEntry = Arc<tokio::sync::Mutex<Option<Credentials>>>;
+
+/// Role credentials kept in memory until they are close to expiring, shared by the endpoints.
+/// Each role has its own lock, so a slow `GetRoleCredentials` only holds up requests for the same
+/// role, and those get its result instead of fetching again.
+#[derive(Default)]
+pub struct CredentialCache {
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+}
+
+impl CredentialCache {
+    pub async fn get(
+        &self,
+        app: &AppHandle,
+        partition: String,
+        account_id: String,
+        role_name: String,
+    ) -> Result<Credentials, ArsdError> {
+        let key = (partition.clone(), account_id.clone(), role_name.clone());
+        let entry = self.entries.lock().unwrap().entry(key).or_default().clone();
+        let mut entry = entry.lock().await;
+        let settings = app.state::<SharedSettings>().current();
+        if let Some(c) = entry.as_ref() {
+            if c.expires_at > Utc::now() + settings.credential_margin() {
+                return Ok(c.clone());
+            }
+        }
+        let part = settings
+            .partition(partition.clone())
+            .ok_or(ArsdError::UnknownPartition(partition))?;
+        let creds = account::get_credentials(part, role_name, account_id, app.clone()).await?;
+        *entry = Some(creds.clone());
+        Ok(creds)
+    }
+
+    /// Drop every role of a partition, so the endpoints stop serving it after signing out.
+    pub fn clear_partition(&self, partition: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(p, _, _), _| p != partition);
+    }
+}
+
+/// Compare secrets without returning early at the first difference, so response times don't
+/// reveal how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,32 +1,52 @@
 use aws_sdk_sso::{self, Error as SsoError};
 use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use tauri::Manager;
 
 use crate::{
-    configuration::Partition,
+    configuration::{Partition, SharedSettings},
     domain::{AccountInfo, RoleInfo},
+    error::ArsdError,
     sql,
     sql::ServiceAccess,
 };
 
-pub async fn list_roles(partition: Partition, token: String, account_id: String) -> Vec<RoleInfo> {
+/// Invalidate `token` with SSO so it can't be used even if a copy of it survives.
+pub async fn logout(partition: &Partition, token: String) -> Result<(), ArsdError> {
+    let config = partition.aws_config().await;
+    let client = aws_sdk_sso::Client::new(&config);
+    client
+        .logout()
+        .access_token(token)
+        .send()
+        .await
+        .map_err(SsoError::from)?;
+    Ok(())
+}
+
+pub async fn list_roles(
+    partition: Partition,
+    token: String,
+    account_id: String,
+) -> Result<Vec<RoleInfo>, ArsdError> {
     let config = partition.aws_config().await;
     let client = aws_sdk_sso::Client::new(&config);
 
     let mut roles: Vec<RoleInfo> = vec![];
     let mut tries: i32 = 0;
-    while tries < 10 {
+    loop {
         let req = client
             .list_account_roles()
             .access_token(token.clone())
             .account_id(account_id.clone());
         match req.send().await.map_err(SsoError::from) {
-            Err(SsoError::TooManyRequestsException(e)) => {
+            Err(SsoError::TooManyRequestsException(e)) if tries < 10 => {
                 log::warn!("Slow down: {:?}", e);
-                std::thread::sleep(std::time::Duration::from_millis(750));
+                tokio::time::sleep(std::time::Duration::from_millis(750)).await;
                 tries += 1;
             }
             Err(e) => {
                 log::warn!("Failed to get roles in {}: {:?}", account_id.clone(), e);
+                return Err(e.into());
             }
             Ok(pgn) => {
                 let l = pgn.role_list();
@@ -40,6 +60,9 @@ pub async fn list_roles(partition: Partition, token: String, account_id: String)
                         account_id: role.account_id.clone().unwrap(),
                         role_name: role.role_name.clone().unwrap(),
                         partition: partition.slug(),
+                        score: None,
+                        last_used_at: None,
+                        use_count: 0,
                     })
                 }
                 break;
@@ -54,13 +77,14 @@ pub async fn list_roles(partition: Partition, token: String, account_id: String)
             .map(|r| { r.role_name.clone() })
             .collect::<Vec<String>>()
     );
-    roles
+    Ok(roles)
 }
 
-pub async fn list_accounts(partition: Partition, app: tauri::AppHandle) -> Vec<AccountInfo> {
-    let candidates = app
-        .db(|db| sql::models::Account::list(db, partition.slug()))
-        .unwrap();
+pub async fn list_accounts(
+    partition: Partition,
+    app: tauri::AppHandle,
+) -> Result<Vec<AccountInfo>, ArsdError> {
+    let candidates = app.db(|db| sql::models::Account::list(db, partition.slug()))?;
     if !candidates.is_empty()
         // only requery if the data is more than 5 hours old
         && candidates.iter().map(|a| a.updated_at).min().unwrap()
@@ -75,15 +99,13 @@ pub async fn list_accounts(partition: Partition, app: tauri::AppHandle) -> Vec<A
                 .collect::<Vec<String>>()
                 .join(", ")
         );
-        return candidates.iter().map(|a| a.as_info()).collect();
+        return Ok(candidates.iter().map(|a| a.as_info()).collect());
     }
 
-    let token = match app
-        .db(|db| sql::models::Token::find(db, partition.slug()))
-        .unwrap()
-    {
+    let token = match app.db(|db| sql::models::Token::find(db, app.secrets(), partition.slug()))? {
         Some(t) => t,
-        None => return candidates.iter().map(|a| a.as_info()).collect(),
+        None if candidates.is_empty() => return Err(ArsdError::NoToken(partition.slug())),
+        None => return Ok(candidates.iter().map(|a| a.as_info()).collect()),
     };
 
     let config = partition.aws_config().await;
@@ -99,7 +121,11 @@ pub async fn list_accounts(partition: Partition, app: tauri::AppHandle) -> Vec<A
         Ok(r) => r,
         Err(e) => {
             log::error!("Failed to get accounts in {}: {:?}", partition.slug(), e);
-            return candidates.iter().map(|a| a.as_info()).collect();
+            // stale accounts are still useful, only fail if there's nothing to show
+            if candidates.is_empty() {
+                return Err(e.into());
+            }
+            return Ok(candidates.iter().map(|a| a.as_info()).collect());
         }
     };
 
@@ -119,8 +145,7 @@ pub async fn list_accounts(partition: Partition, app: tauri::AppHandle) -> Vec<A
             .access_token(token.access_token.clone())
             .next_token(resp.next_token().unwrap().to_string())
             .send()
-            .await
-            .unwrap();
+            .await?;
         for l in resp.account_list() {
             accounts.push(sql::models::Account {
                 partition: partition.slug(),
@@ -131,11 +156,12 @@ pub async fn list_accounts(partition: Partition, app: tauri::AppHandle) -> Vec<A
             });
         }
     }
-    app.db(|db| {
+    app.db(|db| -> Result<(), rusqlite::Error> {
         for a in &accounts {
-            a.insert(db).expect("Failed to insert account in local DB");
+            a.insert(db)?;
         }
-    });
+        Ok(())
+    })?;
 
     log::debug!(
         "Found accounts for {}: {:?}",
@@ -145,7 +171,7 @@ pub async fn list_accounts(partition: Partition, app: tauri::AppHandle) -> Vec<A
             .map(|a| { a.account_name.clone() })
             .collect::<Vec<String>>()
     );
-    accounts.iter().map(|a| a.as_info()).collect()
+    Ok(accounts.iter().map(|a| a.as_info()).collect())
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
@@ -162,31 +188,100 @@ pub async fn get_credentials(
     role_name: String,
     account_id: String,
     app: tauri::AppHandle,
-) -> Credentials {
-    let token = match app
-        .db(|db| sql::models::Token::find(db, partition.slug()))
-        .unwrap()
-    {
+) -> Result<Credentials, ArsdError> {
+    let margin = app.state::<SharedSettings>().current().credential_margin();
+    let cached = app.db(|db| {
+        sql::models::RoleCredentials::find(
+            db,
+            app.secrets(),
+            partition.slug(),
+            account_id.clone(),
+            role_name.clone(),
+            margin,
+        )
+    })?;
+    if let Some(c) = cached {
+        log::debug!(
+            "Using cached credentials for {} in {}",
+            role_name,
+            account_id
+        );
+        return Ok(c.into());
+    }
+
+    let token = match app.db(|db| sql::models::Token::find(db, app.secrets(), partition.slug()))? {
         Some(t) => t,
-        None => todo!("handle missing token"),
+        None => return Err(ArsdError::NoToken(partition.slug())),
     };
 
+    let creds = role_credentials(
+        &partition,
+        token.access_token,
+        role_name.clone(),
+        account_id.clone(),
+    )
+    .await?;
+    app.db_mut(|db| {
+        sql::models::RoleCredentials {
+            partition: partition.slug(),
+            account_id,
+            role_name,
+            access_key_id: creds.access_key_id.clone(),
+            secret_access_key: creds.secret_access_key.clone(),
+            session_token: creds.session_token.clone(),
+            expires_at: creds.expires_at,
+        }
+        .insert(db, app.secrets())
+    })?;
+    Ok(creds)
+}
+
+impl From<sql::models::RoleCredentials> for Credentials {
+    fn from(c: sql::models::RoleCredentials) -> Self {
+        Credentials {
+            access_key_id: c.access_key_id,
+            secret_access_key: c.secret_access_key,
+            session_token: c.session_token,
+            expires_at: c.expires_at,
+        }
+    }
+}
+
+/// Exchange an SSO access token for role credentials. This doesn't touch app state so it can be
+/// shared with the `arsd-credential-process` helper.
+pub async fn role_credentials(
+    partition: &Partition,
+    access_token: String,
+    role_name: String,
+    account_id: String,
+) -> Result<Credentials, ArsdError> {
     let config = partition.aws_config().await;
     let client = aws_sdk_sso::Client::new(&config);
 
     let resp = client
         .get_role_credentials()
-        .access_token(token.access_token)
-        .role_name(role_name)
-        .account_id(account_id)
+        .access_token(access_token)
+        .role_name(role_name.clone())
+        .account_id(account_id.clone())
         .send()
-        .await
-        .unwrap();
-    let creds = resp.role_credentials().unwrap();
-    Credentials {
-        access_key_id: creds.access_key_id().unwrap().to_string(),
-        secret_access_key: creds.secret_access_key().unwrap().to_string(),
-        session_token: creds.session_token().unwrap().to_string(),
-        expires_at: DateTime::from_timestamp(creds.expiration(), 0).unwrap(),
-    }
+        .await?;
+    let creds = match resp.role_credentials() {
+        Some(c) => c,
+        None => {
+            return Err(ArsdError::Sso(format!(
+                "No credentials returned for {role_name} in {account_id}"
+            )))
+        }
+    };
+    Ok(Credentials {
+        access_key_id: creds.access_key_id().unwrap_or_default().to_string(),
+        secret_access_key: creds.secret_access_key().unwrap_or_default().to_string(),
+        session_token: creds.session_token().unwrap_or_default().to_string(),
+        // GetRoleCredentials reports expiration in epoch milliseconds
+        expires_at: DateTime::from_timestamp_millis(creds.expiration()).ok_or_else(|| {
+            ArsdError::Sso(String::from(
+                "Credential expiration is not a valid timestamp",
+            ))
+        })?,
+    })
 }
@@ -3,9 +3,8 @@ use serde_json;
 use url::Url;
 
 use super::account;
-use crate::configuration::Partition;
-
-const AWS_DOMAIN: &str = "aws.amazon.com";
+use crate::configuration::{AwsPartition, Partition};
+use crate::error::ArsdError;
 
 #[derive(serde::Serialize, Debug)]
 struct SignInTokenRequestSession {
@@ -23,22 +22,50 @@ struct SignInTokenResponse {
     token: String,
 }
 
+/// Console page to land on after federating. `service` is the path under the console domain, such
+/// as `s3` or `ec2/v2#Instances`; without one the console home page is used.
+fn destination_url(aws_partition: AwsPartition, region: &str, service: Option<&str>) -> String {
+    let (path, fragment) = match service.map(|s| s.trim_matches('/')) {
+        None | Some("") => ("console", None),
+        Some(s) => match s.split_once('#') {
+            Some((p, f)) => (p.trim_matches('/'), Some(f)),
+            None => (s, None),
+        },
+    };
+    let mut destination =
+        Url::parse(format!("https://{}", aws_partition.console_host(region)).as_str()).unwrap();
+    if path == "home" || path.ends_with("/home") {
+        destination.set_path(path);
+    } else {
+        destination.set_path(format!("{}/home", path).as_str());
+    }
+    destination
+        .query_pairs_mut()
+        .append_pair("region", region)
+        .finish();
+    destination.set_fragment(fragment);
+    destination.to_string()
+}
+
 pub async fn get_console_url(
     account_id: String,
     role_name: String,
     partition: Partition,
     app: tauri::AppHandle,
-) -> String {
+    region: Option<String>,
+    service: Option<String>,
+) -> Result<String, ArsdError> {
+    let region = region.unwrap_or_else(|| partition.console_region_for(&account_id));
+    let aws_partition = partition.aws_partition();
     // Create a signed URL for AWS console
     // https://docs.aws.amazon.com/IAM/latest/UserGuide/example_sts_Scenario_ConstructFederatedUrl_section.html
-    let credentials = account::get_credentials(partition, role_name, account_id, app).await;
+    let credentials = account::get_credentials(partition, role_name, account_id, app).await?;
     let token = SignInTokenRequestSession {
         session_id: credentials.access_key_id,
         session_key: credentials.secret_access_key,
         session_token: credentials.session_token,
     };
-    let mut target =
-        Url::parse(format!("https://signin.{}/federation", AWS_DOMAIN).as_str()).unwrap();
+    let mut target = Url::parse(aws_partition.federation_url().as_str()).unwrap();
     target
         .query_pairs_mut()
         .append_pair("Action", "getSigninToken")
@@ -47,27 +74,21 @@ pub async fn get_console_url(
     log::debug!("Request to send for signin token: {}", target.to_string());
 
     let console_token = reqwest::get(target.to_string())
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json::<SignInTokenResponse>()
-        .await
-        .unwrap();
+        .await?;
 
-    let mut console =
-        Url::parse(format!("https://signin.{}/federation", AWS_DOMAIN).as_str()).unwrap();
+    let mut console = Url::parse(aws_partition.federation_url().as_str()).unwrap();
     console
         .query_pairs_mut()
         .append_pair("Action", "login")
         .append_pair(
             "Destination",
-            format!(
-                "https://{}.console.{}/console/home",
-                "us-west-2", AWS_DOMAIN
-            )
-            .as_str(),
+            destination_url(aws_partition, &region, service.as_deref()).as_str(),
         )
         .append_pair("SigninToken", console_token.token.as_str())
         .finish();
     log::debug!("Finished console URL: {}", console.to_string());
-    console.to_string()
+    Ok(console.to_string())
 }
@@ -10,8 +10,38 @@ pub struct ConfirmDevice {
     pub confirmation_url: String,
 }
 
+/// Sent after each device authorization poll that is still waiting on the user.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct DeviceAuthProgress {
+    pub partition_name: String,
+    pub polling_interval: i32,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Sent when device authorization polling gives up. `expired` is set when the user didn't confirm
+/// before the code expired, so the UI can offer to start over.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct DeviceAuthFailed {
+    pub partition_name: String,
+    pub expired: bool,
+    pub message: String,
+}
+
+/// Sent with the expiry notifications. Role fields are set for copied credentials and empty for
+/// the SSO session.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct SessionExpiring {
+    pub partition_name: String,
+    pub account_id: Option<String>,
+    pub role_name: Option<String>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct PartitionState {
     pub partition_name: String,
     pub state: String,
+    pub message: Option<String>,
 }
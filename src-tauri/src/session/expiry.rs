@@ -0,0 +1,331 @@
+//! Desktop notifications shortly before an SSO session or credentials copied from arsd expire.
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tauri::{AppHandle, Emitter, EventTarget, Manager};
+use tauri_plugin_notification::NotificationExt;
+
+use crate::configuration::SharedSettings;
+use crate::session::events;
+use crate::session::login::REFRESH_MARGIN_MINUTES;
+use crate::{sql, sql::ServiceAccess};
+
+const CHECK_INTERVAL_SECONDS: u64 = 30;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+enum Subject {
+    Token {
+        partition: String,
+    },
+    Credentials {
+        partition: String,
+        account_id: String,
+        role_name: String,
+    },
+}
+
+#[derive(Default)]
+pub struct ExpiryScheduler {
+    vended: Mutex<HashMap<Subject, DateTime<Utc>>>,
+    /// the expiry each subject was last notified about, and the smallest threshold reached
+    notified: Mutex<HashMap<Subject, (DateTime<Utc>, i64)>>,
+}
+
+impl ExpiryScheduler {
+    /// Remember credentials handed to the user, e.g. copied to the clipboard, to warn before they
+    /// expire. Credentials served by the container or IMDS endpoints renew themselves.
+    pub fn vended(
+        &self,
+        partition: String,
+        account_id: String,
+        role_name: String,
+        expires_at: DateTime<Utc>,
+    ) {
+        self.vended.lock().unwrap().insert(
+            Subject::Credentials {
+                partition,
+                account_id,
+                role_name,
+            },
+            expires_at,
+        );
+    }
+
+    /// The smallest threshold `expires_at` is within, unless that threshold was already notified
+    /// for the same expiry. A new expiry (a new token or new credentials) starts over.
+    fn due(&self, subject: &Subject, expires_at: DateTime<Utc>, thresholds: &[i64]) -> Option<i64> {
+        let remaining = expires_at - Utc::now();
+        if remaining <= Duration::zero() {
+            return None;
+        }
+        let threshold = thresholds
+            .iter()
+            .copied()
+            .filter(|m| remaining <= Duration::minutes(*m))
+            .min()?;
+        let mut notified = self.notified.lock().unwrap();
+        match notified.get(subject) {
+            Some((at, t)) if *at == expires_at && *t <= threshold => None,
+            _ => {
+                notified.insert(subject.clone(), (expires_at, threshold));
+                Some(threshold)
+            }
+        }
+    }
+}
+
+/// Check expirations for as long as the app runs.
+pub async fn run(app: AppHandle) {
+    loop {
+        check(&app);
+        tokio::time::sleep(std::time::Duration::from_secs(CHECK_INTERVAL_SECONDS)).await;
+    }
+}
+
+fn check(app: &AppHandle) {
+    let settings = app.state::<SharedSettings>().current();
+    if !settings.notifications.enabled {
+        return;
+    }
+    let scheduler = app.state::<ExpiryScheduler>();
+
+    for partition in settings.partitions.iter() {
+        let token = match app.db(|db| sql::models::Token::find(db, app.secrets(), partition.slug()))
+        {
+            Ok(Some(t)) => t,
+            Ok(None) => continue,
+            Err(e) => {
+                log::error!("Failed to look up token for {}: {:?}", partition.slug(), e);
+                continue;
+            }
+        };
+        let subject = Subject::Token {
+            partition: partition.slug(),
+        };
+        if scheduler
+            .due(
+                &subject,
+                token.expires_at,
+                &token_thresholds(&token, &settings.notifications.token_minutes),
+            )
+            .is_some()
+        {
+            notify(
+                app,
+                format!("{} session expiring", partition.slug()),
+                format!(
+                    "The SSO session expires in {}, open arsd to sign in again",
+                    minutes_left(token.expires_at)
+                ),
+                events::SessionExpiring {
+                    partition_name: partition.slug(),
+                    account_id: None,
+                    role_name: None,
+                    expires_at: token.expires_at,
+                },
+            );
+        }
+    }
+
+    let vended: Vec<(Subject, DateTime<Utc>)> = {
+        let mut vended = scheduler.vended.lock().unwrap();
+        vended.retain(|_, expires_at| *expires_at > Utc::now());
+        vended.iter().map(|(s, e)| (s.clone(), *e)).collect()
+    };
+    for (subject, expires_at) in vended {
+        if scheduler
+            .due(
+                &subject,
+                expires_at,
+                &settings.notifications.credential_minutes,
+            )
+            .is_none()
+        {
+            continue;
+        }
+        if let Subject::Credentials {
+            partition,
+            account_id,
+            role_name,
+        } = subject
+        {
+            notify(
+                app,
+                format!("{} credentials expiring", role_name),
+                format!(
+                    "Credentials copied for {} in {} expire in {}",
+                    role_name,
+                    account_id,
+                    minutes_left(expires_at)
+                ),
+                events::SessionExpiring {
+                    partition_name: partition,
+                    account_id: Some(account_id),
+                    role_name: Some(role_name),
+                    expires_at,
+                },
+            );
+        }
+    }
+}
+
+/// Thresholds for a token's expiry. A token with a refresh token is renewed in the background
+/// `REFRESH_MARGIN_MINUTES` before it expires, so it only gets closer than that when refreshing
+/// failed, and earlier thresholds would warn about every routine refresh.
+fn token_thresholds(token: &sql::models::Token, configured: &[i64]) -> Vec<i64> {
+    if token.refresh_token.is_none() {
+        return configured.to_vec();
+    }
+    let after_refresh: Vec<i64> = configured
+        .iter()
+        .copied()
+        .filter(|m| *m < REFRESH_MARGIN_MINUTES)
+        .collect();
+    if after_refresh.is_empty() {
+        vec![REFRESH_MARGIN_MINUTES / 3]
+    } else {
+        after_refresh
+    }
+}
+
+/// The partition's token was rejected when refreshing it and has been deleted, so the user has to
+/// sign in again.
+pub fn session_ended(app: &AppHandle, partition: String) {
+    if !app
+        .state::<SharedSettings>()
+        .current()
+        .notifications
+        .enabled
+    {
+        return;
+    }
+    notify(
+        app,
+        format!("{} session ended", partition),
+        String::from("The SSO session could not be renewed, open arsd to sign in again"),
+        events::SessionExpiring {
+            partition_name: partition,
+            account_id: None,
+            role_name: None,
+            expires_at: Utc::now(),
+        },
+    );
+}
+
+/// A new client was registered because the one the token was issued to expires soon. The token
+/// stops refreshing when it does, so the user has to sign in again before then.
+pub fn reauthorize_before(app: &AppHandle, partition: String, expires_at: DateTime<Utc>) {
+    if !app
+        .state::<SharedSettings>()
+        .current()
+        .notifications
+        .enabled
+    {
+        return;
+    }
+    notify(
+        app,
+        format!("{} session needs sign-in", partition),
+        format!(
+            "The SSO session can't be renewed after {}, open arsd to re-authorize it",
+            expires_at.with_timezone(&chrono::Local).format("%b %-d, %H:%M")
+        ),
+        events::SessionExpiring {
+            partition_name: partition,
+            account_id: None,
+            role_name: None,
+            expires_at,
+        },
+    );
+}
+
+fn minutes_left(expires_at: DateTime<Utc>) -> String {
+    match (expires_at - Utc::now()).num_minutes() {
+        0 | 1 => String::from("a minute"),
+        m => format!("{} minutes", m),
+    }
+}
+
+/// Desktop notifications can't carry buttons on every platform, so the app also gets an event
+/// and offers to re-authorize from there.
+fn notify(app: &AppHandle, title: String, body: String, payload: events::SessionExpiring) {
+    if let Err(e) = app.notification().builder().title(title).body(body).show() {
+        log::warn!("Failed to show expiry notification: {:?}", e);
+    }
+    app.emit_to(EventTarget::any(), "session_expiring", payload)
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(refresh_token: Option<&str>, minutes_left: i64) -> sql::models::Token {
+        sql::models::Token {
+            partition: String::from("us-east-1-d-123"),
+            token_type: String::from("Bearer"),
+            access_token: String::from("access"),
+            expires_at: Utc::now() + Duration::minutes(minutes_left),
+            refresh_token: refresh_token.map(String::from),
+            client_id: None,
+            client_secret: None,
+        }
+    }
+
+    fn subject() -> Subject {
+        Subject::Token {
+            partition: String::from("us-east-1-d-123"),
+        }
+    }
+
+    #[test]
+    fn token_without_refresh_uses_configured_thresholds() {
+        assert_eq!(token_thresholds(&token(None, 60), &[30, 5]), vec![30, 5]);
+    }
+
+    #[test]
+    fn refreshable_token_skips_thresholds_before_the_refresh() {
+        assert_eq!(
+            token_thresholds(&token(Some("refresh"), 60), &[30, 5]),
+            vec![5]
+        );
+        assert_eq!(
+            token_thresholds(&token(Some("refresh"), 60), &[30]),
+            vec![REFRESH_MARGIN_MINUTES / 3]
+        );
+    }
+
+    #[test]
+    fn refreshable_token_notifies_when_refresh_failed() {
+        let scheduler = ExpiryScheduler::default();
+        // waiting for the background refresh, nothing to say yet
+        let pending = token(Some("refresh"), 20);
+        assert_eq!(
+            scheduler.due(
+                &subject(),
+                pending.expires_at,
+                &token_thresholds(&pending, &[30, 5])
+            ),
+            None
+        );
+        // the refresh should have happened minutes ago
+        let stale = token(Some("refresh"), 4);
+        let thresholds = token_thresholds(&stale, &[30, 5]);
+        assert_eq!(
+            scheduler.due(&subject(), stale.expires_at, &thresholds),
+            Some(5)
+        );
+        assert_eq!(
+            scheduler.due(&subject(), stale.expires_at, &thresholds),
+            None
+        );
+    }
+
+    #[test]
+    fn token_without_refresh_notifies_at_each_threshold() {
+        let scheduler = ExpiryScheduler::default();
+        let t = token(None, 20);
+        assert_eq!(scheduler.due(&subject(), t.expires_at, &[30, 5]), Some(30));
+        assert_eq!(scheduler.due(&subject(), t.expires_at, &[30, 5]), None);
+    }
+}
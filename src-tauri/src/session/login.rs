@@ -1,11 +1,24 @@
+use aws_sdk_ssooidc::operation::create_token::CreateTokenOutput;
 use aws_sdk_ssooidc::{self, Error as SsoIdcError};
 use chrono::{serde::ts_milliseconds, DateTime, Utc};
-use tauri::{AppHandle, Emitter, EventTarget};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Emitter, EventTarget, Manager};
 
 use crate::configuration::Partition;
 use crate::domain::storage::client_name;
+use crate::session::{account, events, expiry};
 use crate::{sql, sql::ServiceAccess};
 
+/// How long before the access token expires that the background refresh kicks in.
+pub(crate) const REFRESH_MARGIN_MINUTES: i64 = 15;
+/// Registrations closer than this to expiring are replaced by the background refresh or the next
+/// sign-in, so a new token's refresh isn't cut short by the client it was issued to.
+const REREGISTER_MARGIN_DAYS: i64 = 7;
+/// Added to the polling interval on each `SlowDownException`, per RFC 8628.
+const SLOW_DOWN_SECONDS: i32 = 5;
+
 // matching type in SessionToolbar.vue
 #[derive(serde::Deserialize, serde::Serialize, PartialEq, Clone, Debug)]
 pub struct ConfirmationInfo {
@@ -44,8 +57,16 @@ pub enum DeviceAuthState {
 #[derive(Clone, Debug)]
 pub enum Event {
     RegisterDevice,
+    /// like `RegisterDevice`, but a token that's still valid is replaced instead of reused
+    Renew,
     StartDeviceAuthorization,
     ConfirmDeviceAuthorization(ConfirmationInfo),
+    RefreshToken,
+    /// register a new client before the current one expires, leaving the token as it is
+    RotateRegistration,
+    /// the token was found expired or was rejected by SSO
+    TokenExpired,
+    Logout,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -54,223 +75,817 @@ pub enum State {
     Registered,                             // non-expired registration is available
     AwaitingConfirmation(ConfirmationInfo), // a token was requested, but the user has to confirm
     Ready,                                  // non-expired token is available
+    Expired, // the user didn't confirm before the device code expired
     Failed { message: String },
 }
 
+impl State {
+    /// Name sent in `partition_state` events, matching `PartitionState` in store/index.ts
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Start => "Start",
+            State::Registered => "Registered",
+            State::AwaitingConfirmation(_) => "AwaitingConfirmation",
+            State::Ready => "Ready",
+            State::Expired => "Expired",
+            State::Failed { .. } => "Failed",
+        }
+    }
+}
+
+/// The sign-in state machine for one partition. One lives for each partition in the
+/// `SessionManager`, so commands, device polling and the background refresh all see the same
+/// state.
 pub struct SessionState {
     partition: Partition,
     app: AppHandle,
     oidc: aws_sdk_ssooidc::Client,
     state: State,
+    /// ignore a token that is still valid and get a new one, set by `Event::Renew`
+    renew: bool,
 }
 impl SessionState {
-    /*
-     Event loop for each partition:
-     - `authorize_device` emitted by `main` on app start
-     - `confirm_device` emitted by SSOSession if user confirmation is required
-     - `partition_state` emitted by SSOSession when the partition state changes
-    */
-    pub async fn new(
-        app: AppHandle,
-        partition: Partition,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        log::info!("starting login check");
+    pub async fn new(app: AppHandle, partition: Partition) -> Self {
+        log::info!("starting session for {}", partition.slug());
         let config = partition.aws_config().await;
-        Ok(Self {
+        Self {
             partition,
             app,
             state: State::Start,
             oidc: aws_sdk_ssooidc::Client::new(&config),
-        })
+            renew: false,
+        }
     }
 
+    pub fn state(&self) -> State {
+        self.state.clone()
+    }
+
+    /// Apply `event` and emit `partition_state` with the state it leads to.
     pub async fn next(&mut self, event: Event) -> State {
-        if self
+        let state = self.step(event.clone()).await;
+        log::debug!(
+            "{}: {:?} + {:?} -> {:?}",
+            self.partition.slug(),
+            self.state,
+            event,
+            state
+        );
+        if state == State::Ready {
+            self.renew = false;
+        }
+        self.state = state.clone();
+        self.app
+            .emit_to(
+                EventTarget::any(),
+                "partition_state",
+                events::PartitionState {
+                    partition_name: self.partition.slug(),
+                    state: state.name().to_string(),
+                    message: match &state {
+                        State::Failed { message } => Some(message.clone()),
+                        _ => None,
+                    },
+                },
+            )
+            .unwrap();
+        state
+    }
+
+    fn has_token(&self) -> bool {
+        match self
             .app
-            .db(|db| sql::models::Token::find(db, self.partition.slug()))
-            .unwrap()
-            .is_some()
+            .db(|db| sql::models::Token::find(db, self.app.secrets(), self.partition.slug()))
         {
-            self.state = State::Ready;
-            log::info!("found valid token, short-circuiting login");
-            return State::Ready;
-        };
+            Ok(t) => t.is_some(),
+            Err(e) => {
+                log::error!(
+                    "Failed to look up token for {}: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+                false
+            }
+        }
+    }
 
-        match (self.state.clone(), event.clone()) {
-            (State::Start, Event::RegisterDevice) => {
-                if self
+    fn registration(&self) -> Option<sql::models::Registration> {
+        match self
+            .app
+            .db(|db| sql::models::Registration::find(db, self.app.secrets(), self.partition.slug()))
+        {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!(
+                    "Failed to look up registration for {}: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    async fn step(&mut self, event: Event) -> State {
+        match (self.state.clone(), event) {
+            (_, Event::Logout) => self.logout().await,
+            (State::Ready, Event::TokenExpired) => {
+                if let Err(e) = self
                     .app
-                    .db(|db| sql::models::Registration::find(db, self.partition.slug()))
-                    .unwrap()
-                    .is_none()
+                    .db(|db| sql::models::Token::delete(db, self.partition.slug()))
                 {
-                    let req = self
-                        .oidc
-                        .register_client()
-                        .client_name(client_name())
-                        .client_type("public");
-                    log::info!(
-                        "sending req for new {} secret name={:?}, type={:?}, scopes={:?}",
+                    log::error!(
+                        "Failed to delete token for {}: {:?}",
                         self.partition.slug(),
-                        req.get_client_name(),
-                        req.get_client_type(),
-                        req.get_scopes()
+                        e
                     );
-                    let r = req.send().await.expect("Error registering client");
-                    self.app.db(|db| {
-                        let m = sql::models::Registration {
-                            partition: self.partition.slug(),
-                            client_id: r.client_id().unwrap().to_string(),
-                            client_secret: r.client_secret().unwrap().to_string(),
-                            issued_at: DateTime::<Utc>::from_timestamp(r.client_id_issued_at(), 0)
-                                .expect("client ID issue timestamp should parse"),
-                            expires_at: DateTime::<Utc>::from_timestamp(
-                                r.client_secret_expires_at(),
-                                0,
-                            )
-                            .expect("client ID expiry timestamp should parse"),
-                        };
-                        m.insert(db)
-                            .expect("Failed to save registration for the current partition");
-                    });
-                };
-                self.state = State::Registered;
-                self.app
-                    .emit_to(
-                        EventTarget::any(),
-                        "needs_confirmation",
-                        self.partition.slug(),
-                    )
-                    .unwrap();
-                State::Registered
-                // learn what boxing is if we want this state machine to be recursive
-                // self.next(Event::StartDeviceAuthorization).await
+                }
+                State::Start
+            }
+            // nothing to expire, or a new sign-in is already under way
+            (
+                state @ (State::Start
+                | State::Registered
+                | State::AwaitingConfirmation(_)
+                | State::Expired
+                | State::Failed { .. }),
+                Event::TokenExpired,
+            ) => state,
+            (_, Event::RefreshToken) => self.refresh().await,
+            // the device code being polled belongs to the current client
+            (State::AwaitingConfirmation(c), Event::RotateRegistration) => {
+                State::AwaitingConfirmation(c)
+            }
+            (state, Event::RotateRegistration) => self.rotate_registration(state).await,
+
+            (_, Event::RegisterDevice | Event::StartDeviceAuthorization)
+                if !self.renew && self.has_token() =>
+            {
+                log::info!("found valid token, short-circuiting login");
+                State::Ready
+            }
+            // don't hand out a second code while the first can still be confirmed
+            (
+                State::AwaitingConfirmation(c),
+                Event::RegisterDevice | Event::Renew | Event::StartDeviceAuthorization,
+            ) if c.expires_at > Utc::now() => State::AwaitingConfirmation(c),
+            (_, Event::Renew) => {
+                self.renew = true;
+                self.register().await
             }
+            (
+                State::Start
+                | State::Registered
+                | State::AwaitingConfirmation(_)
+                | State::Ready
+                | State::Expired
+                | State::Failed { .. },
+                Event::RegisterDevice,
+            ) => self.register().await,
             (State::Registered, Event::StartDeviceAuthorization) => {
-                if self
-                    .app
-                    .db(|db| sql::models::Token::find(db, self.partition.slug()))
-                    .unwrap()
-                    .is_some()
-                {
-                    self.state = State::Ready;
-                    return State::Ready;
-                };
+                self.start_device_authorization().await
+            }
+            (
+                State::Start
+                | State::AwaitingConfirmation(_)
+                | State::Ready
+                | State::Expired
+                | State::Failed { .. },
+                Event::StartDeviceAuthorization,
+            ) => match self.register().await {
+                State::Registered => self.start_device_authorization().await,
+                other => other,
+            },
 
-                log::info!("no valid token found");
-                let registration = match self
-                    .app
-                    .db(|db| sql::models::Registration::find(db, self.partition.slug()))
-                    .unwrap()
-                {
-                    None => {
-                        log::warn!("no registration found, returning to start");
-                        self.state = State::Start;
-                        return State::Start;
-                    }
-                    Some(r) => r,
+            (State::Ready, Event::ConfirmDeviceAuthorization(_)) if !self.renew => State::Ready,
+            (_, Event::ConfirmDeviceAuthorization(cc)) => self.confirm(cc).await,
+        }
+    }
+
+    /// Revoke the token with SSO and forget it. The local token is deleted even when SSO can't
+    /// be reached, since the user asked to be signed out.
+    async fn logout(&mut self) -> State {
+        self.renew = false;
+        let token = match self
+            .app
+            .db(|db| sql::models::Token::find(db, self.app.secrets(), self.partition.slug()))
+        {
+            Ok(t) => t,
+            Err(e) => {
+                log::error!(
+                    "Failed to look up token for {}: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+                None
+            }
+        };
+        if let Some(t) = token {
+            if let Err(e) = account::logout(&self.partition, t.access_token).await {
+                log::warn!(
+                    "Failed to revoke token for {}: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+            }
+        }
+        if let Err(e) = self
+            .app
+            .db(|db| sql::models::Token::delete(db, self.partition.slug()))
+        {
+            log::error!(
+                "Failed to delete token for {}: {:?}",
+                self.partition.slug(),
+                e
+            );
+        }
+        State::Start
+    }
+
+    /// Drop the registration after SSO rejected it, so the next sign-in registers again.
+    fn forget_registration(&self) {
+        if let Err(e) = self
+            .app
+            .db(|db| sql::models::Registration::delete(db, self.partition.slug()))
+        {
+            log::error!(
+                "Failed to delete registration for {}: {:?}",
+                self.partition.slug(),
+                e
+            );
+        }
+    }
+
+    /// Make sure there's a client registration, creating one if there's none or it expires soon.
+    async fn register(&mut self) -> State {
+        match self.registration() {
+            Some(r)
+                if r.expires_at > Utc::now() + chrono::Duration::days(REREGISTER_MARGIN_DAYS) =>
+            {
+                return State::Registered;
+            }
+            Some(r) => log::info!(
+                "registration for {} expires at {}, registering again",
+                self.partition.slug(),
+                r.expires_at
+            ),
+            None => {}
+        }
+        // `sso:account:access` is what gets us a refresh token from `create_token`
+        let req = self
+            .oidc
+            .register_client()
+            .client_name(client_name())
+            .client_type("public")
+            .set_scopes(Some(self.partition.scopes()));
+        log::info!(
+            "sending req for new {} secret name={:?}, type={:?}, scopes={:?}",
+            self.partition.slug(),
+            req.get_client_name(),
+            req.get_client_type(),
+            req.get_scopes()
+        );
+        let r = match req.send().await {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!(
+                    "Failed to register client for {}: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+                return State::Failed {
+                    message: String::from("Failed to register with SSO"),
                 };
-                let req = self
-                    .oidc
-                    .start_device_authorization()
-                    .client_id(registration.client_id)
-                    .client_secret(registration.client_secret)
-                    .start_url(self.partition.sso_start_url());
-
-                log::info!(
-                    "starting device req id={:?} url={:?} secret={:?}",
-                    req.get_client_id(),
-                    req.get_start_url(),
-                    req.get_client_secret(),
+            }
+        };
+        let saved = self.app.db(|db| {
+            sql::models::Registration {
+                partition: self.partition.slug(),
+                client_id: r.client_id().unwrap_or_default().to_string(),
+                client_secret: r.client_secret().unwrap_or_default().to_string(),
+                issued_at: DateTime::<Utc>::from_timestamp(r.client_id_issued_at(), 0)
+                    .unwrap_or_else(Utc::now),
+                expires_at: DateTime::<Utc>::from_timestamp(r.client_secret_expires_at(), 0)
+                    .unwrap_or_else(Utc::now),
+            }
+            .insert(db, self.app.secrets())
+        });
+        if let Err(e) = saved {
+            log::error!(
+                "Failed to save registration for {}: {:?}",
+                self.partition.slug(),
+                e
+            );
+            return State::Failed {
+                message: String::from("Failed to save the SSO registration"),
+            };
+        }
+        self.app
+            .emit_to(
+                EventTarget::any(),
+                "needs_confirmation",
+                self.partition.slug(),
+            )
+            .unwrap();
+        State::Registered
+    }
+
+    /// Register a new client for the next sign-in while the token keeps refreshing with the one
+    /// it was issued to, and tell the user to sign in again before that one expires.
+    async fn rotate_registration(&mut self, state: State) -> State {
+        let previous = match self.registration() {
+            Some(r) => r,
+            None => return state,
+        };
+        match self.register().await {
+            State::Failed { message } => log::warn!(
+                "Could not replace the registration for {}, will retry: {}",
+                self.partition.slug(),
+                message
+            ),
+            _ => expiry::reauthorize_before(&self.app, self.partition.slug(), previous.expires_at),
+        }
+        state
+    }
+
+    async fn start_device_authorization(&mut self) -> State {
+        let registration = match self.registration() {
+            None => {
+                log::warn!("no registration found, returning to start");
+                return State::Start;
+            }
+            Some(r) => r,
+        };
+        let req = self
+            .oidc
+            .start_device_authorization()
+            .client_id(registration.client_id)
+            .client_secret(registration.client_secret)
+            .start_url(self.partition.sso_start_url());
+
+        log::info!(
+            "starting device req id={:?} url={:?}",
+            req.get_client_id(),
+            req.get_start_url(),
+        );
+        match req.send().await.map_err(SsoIdcError::from) {
+            Err(SsoIdcError::InvalidClientException(e)) => {
+                log::warn!(
+                    "Registration for {} was rejected: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+                self.forget_registration();
+                State::Start
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to start device auth for {}: {:?}",
+                    self.partition.slug(),
+                    e
                 );
-                let confirmation = match req.send().await {
+                State::Failed {
+                    message: "Failed to start device auth".to_string(),
+                }
+            }
+            Ok(resp) => State::AwaitingConfirmation(ConfirmationInfo {
+                partition: self.partition.slug(),
+                user_code: resp.user_code().unwrap_or_default().to_string(),
+                device_code: resp.device_code().unwrap_or_default().to_string(),
+                expires_at: Utc::now() + chrono::Duration::seconds(resp.expires_in().into()),
+                confirmation_url: resp
+                    .verification_uri_complete()
+                    .unwrap_or_default()
+                    .to_string(),
+                polling_interval: resp.interval(),
+            }),
+        }
+    }
+
+    async fn confirm(&mut self, mut cc: ConfirmationInfo) -> State {
+        if cc.expires_at <= Utc::now() {
+            return State::Expired;
+        }
+        let registration = match self.registration() {
+            None => {
+                log::warn!("no registration found, returning to start");
+                return State::Start;
+            }
+            Some(r) => r,
+        };
+
+        let req = self
+            .oidc
+            .create_token()
+            .client_id(registration.client_id.clone())
+            .client_secret(registration.client_secret.clone())
+            .device_code(cc.device_code.clone())
+            .grant_type(String::from("urn:ietf:params:oauth:grant-type:device_code"));
+        log::info!(
+            "Sending CreateToken request: client_id={:?}, device_code={:?}, grant_type={:?}",
+            req.get_client_id(),
+            req.get_device_code(),
+            req.get_grant_type(),
+        );
+        match req.send().await.map_err(SsoIdcError::from) {
+            Ok(resp) => {
+                match self.save_token(&resp, &registration.client_id, &registration.client_secret) {
+                    Ok(()) => State::Ready,
                     Err(e) => {
-                        log::error!(
-                            "Failed to start device auth for {}: {:?}",
-                            self.partition.slug(),
-                            e
-                        );
-                        return State::Failed {
-                            message: "Failed to start device auth".to_string(),
-                        };
+                        log::error!("Failed to save token: {:?}", e);
+                        State::Failed {
+                            message: String::from("Failed to save the SSO token"),
+                        }
                     }
-                    Ok(resp) => ConfirmationInfo {
-                        partition: self.partition.slug(),
-                        user_code: resp.user_code().unwrap().to_string(),
-                        device_code: resp.device_code().unwrap().to_string(),
-                        expires_at: Utc::now()
-                            + chrono::Duration::seconds(resp.expires_in().into()),
-                        confirmation_url: resp.verification_uri_complete().unwrap().to_string(),
-                        polling_interval: resp.interval(),
-                    },
-                };
+                }
+            }
+            Err(SsoIdcError::AuthorizationPendingException(e)) => {
+                log::info!("Auth Pending: {:?}", e);
+                State::AwaitingConfirmation(cc)
+            }
+            Err(SsoIdcError::SlowDownException(e)) => {
+                log::info!("Slow down: {:?}", e);
+                cc.polling_interval += SLOW_DOWN_SECONDS;
+                State::AwaitingConfirmation(cc)
+            }
+            Err(SsoIdcError::ExpiredTokenException(e)) => {
+                log::info!("Device code expired: {:?}", e);
+                State::Expired
+            }
+            Err(SsoIdcError::InvalidClientException(e)) => {
+                // the device code belongs to the rejected client, so sign-in starts over
+                log::warn!(
+                    "Registration for {} was rejected: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+                self.forget_registration();
+                State::Start
+            }
+            Err(SsoIdcError::AccessDeniedException(e)) => {
+                log::warn!("Device authorization denied: {:?}", e);
+                State::Failed {
+                    message: String::from("Sign-in was denied in the browser"),
+                }
+            }
+            Err(e) => {
+                // likely transient, keep polling until the code expires
+                log::error!("Error confirming device registration: {:?}", e);
+                State::AwaitingConfirmation(cc)
+            }
+        }
+    }
 
-                self.state = State::AwaitingConfirmation(confirmation.clone());
-                State::AwaitingConfirmation(confirmation)
+    async fn refresh(&mut self) -> State {
+        let token = match self
+            .app
+            .db(|db| {
+                sql::models::Token::find_refreshable(db, self.app.secrets(), self.partition.slug())
+            })
+            .unwrap_or_default()
+        {
+            Some(t) if t.refresh_token.is_some() => t,
+            _ => {
+                log::info!("no refresh token for {}", self.partition.slug());
+                return State::Start;
             }
-            (_, Event::ConfirmDeviceAuthorization(cc)) => {
-                let registration = match self
-                    .app
-                    .db(|db| sql::models::Registration::find(db, self.partition.slug()))
-                    .unwrap()
-                {
-                    None => {
-                        log::warn!("no registration found, returning to start");
-                        self.state = State::Start;
-                        return State::Start;
-                    }
-                    Some(r) => r,
-                };
+        };
+        // tokens saved before the client was recorded were issued to the current registration
+        let (client_id, client_secret) = match (token.client_id, token.client_secret) {
+            (Some(id), Some(secret)) => (id, secret),
+            _ => match self.registration() {
+                None => {
+                    log::warn!("no registration found, returning to start");
+                    return State::Start;
+                }
+                Some(r) => (r.client_id, r.client_secret),
+            },
+        };
 
-                let req = self
-                    .oidc
-                    .create_token()
-                    .client_id(registration.client_id)
-                    .client_secret(registration.client_secret)
-                    .device_code(cc.device_code.clone())
-                    .grant_type(String::from("urn:ietf:params:oauth:grant-type:device_code"));
-                log::info!(
-                "Sending CreateToken request: client_id={:?}, client_secret={:?}, device_code={:?}, code={:?}, grant_type={:?}, refresh_token={:?}, scope={:?}",
-                req.get_client_id(),
-                req.get_client_secret().is_some(),
-                req.get_device_code(),
-                req.get_code(),
-                req.get_grant_type(),
-                req.get_refresh_token(),
-                req.get_scope()
-            );
-                match req.send().await.map_err(SsoIdcError::from) {
-                    Ok(resp) => {
-                        log::info!("Got response: {:?}", resp);
-                        self.app.db_mut(|db| {
-                            sql::models::Token {
-                                partition: self.partition.slug(),
-                                token_type: resp.token_type().unwrap().to_string(),
-                                access_token: resp.access_token().unwrap().to_string(),
-                                expires_at: Utc::now()
-                                    + chrono::Duration::seconds(resp.expires_in().into()),
-                            }
-                            .insert(db)
-                            .unwrap()
-                        });
-                        self.state = State::Ready;
-                        State::Ready
-                    }
-                    Err(SsoIdcError::AuthorizationPendingException(e)) => {
-                        log::info!("Auth Pending: {:?}", e);
-                        State::AwaitingConfirmation(cc)
-                    }
-                    Err(SsoIdcError::SlowDownException(e)) => {
-                        log::info!("Slow down: {:?}", e);
-                        State::AwaitingConfirmation(cc)
-                    }
+        let req = self
+            .oidc
+            .create_token()
+            .client_id(client_id.clone())
+            .client_secret(client_secret.clone())
+            .refresh_token(token.refresh_token.unwrap_or_default())
+            .grant_type(String::from("refresh_token"));
+        match req.send().await.map_err(SsoIdcError::from) {
+            Ok(resp) => {
+                log::info!("refreshed token for {}", self.partition.slug());
+                match self.save_token(&resp, &client_id, &client_secret) {
+                    Ok(()) => State::Ready,
                     Err(e) => {
-                        log::error!("Error confirming device registration: {:?}", e);
-                        State::AwaitingConfirmation(cc)
+                        log::error!("Failed to save token: {:?}", e);
+                        State::Start
+                    }
+                }
+            }
+            Err(
+                e @ (SsoIdcError::InvalidGrantException(_)
+                | SsoIdcError::ExpiredTokenException(_)
+                | SsoIdcError::InvalidClientException(_)),
+            ) => {
+                // the refresh token is expired or revoked, the user has to confirm a new device
+                // authorization so there's no point keeping it around
+                log::warn!(
+                    "Failed to refresh token for {}: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+                // a token from a replaced client says nothing about the current registration
+                if matches!(e, SsoIdcError::InvalidClientException(_))
+                    && self
+                        .registration()
+                        .is_some_and(|r| r.client_id == client_id)
+                {
+                    self.forget_registration();
+                }
+                if let Err(e) = self
+                    .app
+                    .db(|db| sql::models::Token::delete(db, self.partition.slug()))
+                {
+                    log::error!("Failed to delete token: {:?}", e);
+                }
+                expiry::session_ended(&self.app, self.partition.slug());
+                State::Start
+            }
+            Err(e) => {
+                // offline, throttled or SSO had a hiccup: keep the refresh token and try again on
+                // the next background refresh
+                log::warn!(
+                    "Could not refresh token for {}, will retry: {:?}",
+                    self.partition.slug(),
+                    e
+                );
+                if self.has_token() {
+                    State::Ready
+                } else {
+                    State::Failed {
+                        message: String::from("Could not reach SSO to refresh the session"),
                     }
                 }
             }
-            _ => todo!("event={:?} is not legal for state={:?}", event, self.state),
         }
     }
+
+    fn save_token(
+        &self,
+        resp: &CreateTokenOutput,
+        client_id: &str,
+        client_secret: &str,
+    ) -> Result<(), rusqlite::Error> {
+        self.app.db_mut(|db| {
+            sql::models::Token {
+                partition: self.partition.slug(),
+                token_type: resp.token_type().unwrap_or("Bearer").to_string(),
+                access_token: resp.access_token().unwrap_or_default().to_string(),
+                expires_at: Utc::now() + chrono::Duration::seconds(resp.expires_in().into()),
+                refresh_token: resp.refresh_token().map(|t| t.to_string()),
+                client_id: Some(client_id.to_string()),
+                client_secret: Some(client_secret.to_string()),
+            }
+            .insert(db, self.app.secrets())
+        })
+    }
+}
+
+/// The long-lived `SessionState` of every partition, created on first use.
+#[derive(Default)]
+pub struct SessionManager {
+    sessions: tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<SessionState>>>>,
+}
+
+impl SessionManager {
+    pub async fn session(
+        &self,
+        app: &AppHandle,
+        partition: &Partition,
+    ) -> Arc<tokio::sync::Mutex<SessionState>> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(s) = sessions.get(&partition.slug()) {
+            return s.clone();
+        }
+        let s = Arc::new(tokio::sync::Mutex::new(
+            SessionState::new(app.clone(), partition.clone()).await,
+        ));
+        sessions.insert(partition.slug(), s.clone());
+        s
+    }
+
+    /// Send `TokenExpired` to every ready session whose token is gone or expired.
+    pub async fn expire_stale(&self) {
+        let sessions: Vec<_> = self.sessions.lock().await.values().cloned().collect();
+        for session in sessions {
+            let mut s = session.lock().await;
+            if s.state == State::Ready && !s.has_token() {
+                s.next(Event::TokenExpired).await;
+            }
+        }
+    }
+}
+
+/// Background device authorization polling, at most one task per partition. Polling runs in the
+/// backend so sign-in completes while the window is hidden or reloading.
+#[derive(Default)]
+pub struct DevicePollers {
+    tasks: Mutex<HashMap<String, (ConfirmationInfo, JoinHandle<()>)>>,
+}
+
+impl DevicePollers {
+    /// Poll for `confirmation` until the user confirms it or it expires, replacing any earlier
+    /// polling for the partition.
+    pub fn start(&self, app: AppHandle, partition: Partition, confirmation: ConfirmationInfo) {
+        let task = tauri::async_runtime::spawn(poll_device_authorization(
+            app,
+            partition.clone(),
+            confirmation.clone(),
+        ));
+        if let Some((_, previous)) = self
+            .tasks
+            .lock()
+            .unwrap()
+            .insert(partition.slug(), (confirmation, task))
+        {
+            previous.abort();
+        }
+    }
+
+    /// The confirmation still being polled for, so a reloaded window can show it again instead
+    /// of starting over.
+    pub fn pending(&self, partition: &str) -> Option<ConfirmationInfo> {
+        self.tasks
+            .lock()
+            .unwrap()
+            .get(partition)
+            .map(|(c, _)| c.clone())
+            .filter(|c| c.expires_at > Utc::now())
+    }
+
+    /// Stop polling for the partition, e.g. after signing out.
+    pub fn stop(&self, partition: &str) {
+        if let Some((_, task)) = self.tasks.lock().unwrap().remove(partition) {
+            task.abort();
+        }
+    }
+
+    fn finished(&self, confirmation: &ConfirmationInfo) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks
+            .get(&confirmation.partition)
+            .is_some_and(|(c, _)| c.device_code == confirmation.device_code)
+        {
+            tasks.remove(&confirmation.partition);
+        }
+    }
+}
+
+async fn poll_device_authorization(
+    app: AppHandle,
+    partition: Partition,
+    mut confirmation: ConfirmationInfo,
+) {
+    let first = confirmation.clone();
+    let failed = |expired: bool, message: String| {
+        app.emit_to(
+            EventTarget::any(),
+            "device_auth_failed",
+            events::DeviceAuthFailed {
+                partition_name: partition.slug(),
+                expired,
+                message,
+            },
+        )
+        .unwrap();
+    };
+    let session = app
+        .state::<SessionManager>()
+        .session(&app, &partition)
+        .await;
+    loop {
+        let interval = std::time::Duration::from_secs(confirmation.polling_interval.max(1) as u64);
+        tokio::time::sleep(interval).await;
+        let state = session
+            .lock()
+            .await
+            .next(Event::ConfirmDeviceAuthorization(confirmation.clone()))
+            .await;
+        match state {
+            State::AwaitingConfirmation(c) => {
+                confirmation = c;
+                app.emit_to(
+                    EventTarget::any(),
+                    "device_auth_progress",
+                    events::DeviceAuthProgress {
+                        partition_name: partition.slug(),
+                        polling_interval: confirmation.polling_interval,
+                        expires_at: confirmation.expires_at,
+                    },
+                )
+                .unwrap();
+            }
+            State::Ready => {
+                log::info!("Device authorization confirmed for {}", partition.slug());
+                app.emit_to(
+                    EventTarget::any(),
+                    "token_ready",
+                    events::AuthorizeDevice {
+                        partition_name: partition.slug(),
+                    },
+                )
+                .unwrap();
+                break;
+            }
+            State::Expired => {
+                failed(
+                    true,
+                    String::from("The confirmation code expired before it was approved"),
+                );
+                break;
+            }
+            State::Failed { message } => {
+                failed(false, message);
+                break;
+            }
+            other => {
+                log::warn!(
+                    "Device authorization for {} ended in state {:?}",
+                    partition.slug(),
+                    other
+                );
+                failed(
+                    false,
+                    String::from("The device registration was lost, use Refresh to sign in again"),
+                );
+                break;
+            }
+        }
+    }
+    app.state::<DevicePollers>().finished(&first);
+}
+
+/// Whether `token` is expired or close enough to expiring that it should be refreshed.
+pub fn refresh_due(token: &sql::models::Token) -> bool {
+    token.expires_at < Utc::now() + chrono::Duration::minutes(REFRESH_MARGIN_MINUTES)
+}
+
+/// Whether the token was issued to a registration that is about to expire, so a new client
+/// should be registered while the token can still be refreshed.
+fn rotation_due(token: &sql::models::Token, registration: &sql::models::Registration) -> bool {
+    token.client_id.as_ref() == Some(&registration.client_id)
+        && registration.expires_at < Utc::now() + chrono::Duration::days(REREGISTER_MARGIN_DAYS)
+}
+
+/// Renew the partition's token with its refresh token shortly before it expires, so the session
+/// continues without sending the user back through the browser confirmation. A registration that
+/// expires soon is replaced in the same pass.
+pub async fn refresh_if_expiring(app: AppHandle, partition: Partition) {
+    let token = match app
+        .db(|db| sql::models::Token::find_refreshable(db, app.secrets(), partition.slug()))
+    {
+        Ok(Some(t)) => t,
+        Ok(None) => return,
+        Err(e) => {
+            log::error!("Failed to look up token for {}: {:?}", partition.slug(), e);
+            return;
+        }
+    };
+
+    if refresh_due(&token) {
+        let session = app
+            .state::<SessionManager>()
+            .session(&app, &partition)
+            .await;
+        let state = session.lock().await.next(Event::RefreshToken).await;
+        if state != State::Ready {
+            return;
+        }
+        app.emit_to(
+            EventTarget::any(),
+            "token_ready",
+            events::AuthorizeDevice {
+                partition_name: partition.slug(),
+            },
+        )
+        .unwrap();
+    }
+
+    let rotate =
+        match app.db(|db| sql::models::Registration::find(db, app.secrets(), partition.slug())) {
+            Ok(r) => r.is_some_and(|r| rotation_due(&token, &r)),
+            Err(e) => {
+                log::error!(
+                    "Failed to look up registration for {}: {:?}",
+                    partition.slug(),
+                    e
+                );
+                false
+            }
+        };
+    if rotate {
+        let session = app
+            .state::<SessionManager>()
+            .session(&app, &partition)
+            .await;
+        session.lock().await.next(Event::RotateRegistration).await;
+    }
 }
@@ -1,4 +1,5 @@
 pub mod account;
 pub mod console_url;
 pub mod events;
+pub mod expiry;
 pub mod login;
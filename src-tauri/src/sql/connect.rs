@@ -2,6 +2,8 @@ use rusqlite::Connection;
 use std::sync::Mutex;
 use tauri::{AppHandle, Manager, State};
 
+use crate::secrets::SecretStore;
+
 pub struct SqlRepo {
     pub conn: Mutex<Option<Connection>>,
 }
@@ -14,6 +16,9 @@ pub trait ServiceAccess {
     fn db_mut<F, TResult>(&self, operation: F) -> TResult
     where
         F: FnOnce(&mut Connection) -> TResult;
+
+    /// Key for the encrypted columns read and written by the `creds` models.
+    fn secrets(&self) -> &SecretStore;
 }
 
 impl ServiceAccess for AppHandle {
@@ -38,4 +43,8 @@ impl ServiceAccess for AppHandle {
 
         operation(db)
     }
+
+    fn secrets(&self) -> &SecretStore {
+        self.state::<SecretStore>().inner()
+    }
 }
@@ -1,39 +1,24 @@
-use rusqlite::Connection;
-use std::{fs, path::PathBuf};
+use rusqlite::{Connection, OpenFlags};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-const CURRENT_DB_VERSION: u32 = 2;
+use crate::error::ArsdError;
 
-/// Initializes the database connection, creating the .sqlite file if needed, and upgrading the database
-/// if it's out of date.
-pub fn initialize_database(app_dir: PathBuf) -> Result<Connection, rusqlite::Error> {
-    fs::create_dir_all(&app_dir).expect("The app data directory should be created.");
-    let sqlite_path = app_dir.join("arsd.sqlite");
-
-    let mut db = Connection::open(sqlite_path)?;
-
-    let mut user_pragma = db.prepare("PRAGMA user_version")?;
-    let existing_user_version: u32 = user_pragma.query_row([], |row| row.get(0))?;
-    drop(user_pragma);
-
-    upgrade_database_if_needed(&mut db, existing_user_version)?;
-
-    Ok(db)
+/// One schema change, applied in its own transaction that also sets `user_version` to `version`.
+/// Migrations that have shipped must never be edited, add a new one instead.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    sql: &'static str,
 }
 
-/// Upgrades the database to the current version.
-pub fn upgrade_database_if_needed(
-    db: &mut Connection,
-    existing_version: u32,
-) -> Result<(), rusqlite::Error> {
-    if existing_version < CURRENT_DB_VERSION {
-        db.pragma_update(None, "journal_mode", "WAL")?;
-
-        let tx = db.transaction()?;
-
-        tx.pragma_update(None, "user_version", CURRENT_DB_VERSION)?;
-
-        tx.execute_batch(
-            "CREATE TABLE IF NOT EXISTS history (
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "create the initial tables",
+        sql: "CREATE TABLE IF NOT EXISTS history (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 timestamp TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
                 partition TEXT NOT NULL,
@@ -76,10 +61,325 @@ pub fn upgrade_database_if_needed(
                 value TEXT NOT NULL
             );
             ",
-        )?;
+    },
+    Migration {
+        version: 3,
+        // registrations from older versions didn't request any scopes, so they can't be used to
+        // get refresh tokens. Dropping them makes the next login register a new client.
+        description: "store refresh tokens",
+        sql: "ALTER TABLE tokens ADD COLUMN refresh_token TEXT;
+            DELETE FROM registrations WHERE true;
+            ",
+    },
+    Migration {
+        version: 4,
+        description: "cache role credentials",
+        sql: "CREATE TABLE IF NOT EXISTS role_credentials (
+                partition TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                role_name TEXT NOT NULL,
+                access_key_id TEXT NOT NULL,
+                secret_access_key TEXT NOT NULL,
+                session_token TEXT NOT NULL,
+                expires_at TIMESTAMP NOT NULL,
+                PRIMARY KEY (partition, account_id, role_name)
+            );
+            ",
+    },
+    Migration {
+        version: 5,
+        // `NOT_NULL` was parsed as part of the column type, so the constraint never applied.
+        // SQLite can't alter a column constraint in place, so the table is rebuilt.
+        description: "make roles.partition NOT NULL",
+        sql: "CREATE TABLE roles_new (
+                partition TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                role_name TEXT NOT NULL,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (partition, account_id, role_name)
+            );
+            INSERT INTO roles_new (partition, account_id, role_name, updated_at)
+                SELECT partition, account_id, role_name, updated_at FROM roles
+                WHERE partition IS NOT NULL;
+            DROP TABLE roles;
+            ALTER TABLE roles_new RENAME TO roles;
+            ",
+    },
+    Migration {
+        version: 6,
+        // a refresh token only works with the client it was issued to, so it has to outlive the
+        // registration being replaced
+        description: "remember the client each token was issued to",
+        sql: "ALTER TABLE tokens ADD COLUMN client_id TEXT;
+            ALTER TABLE tokens ADD COLUMN client_secret TEXT;
+            ",
+    },
+];
+
+/// Version of the newest migration, which this build expects the database to be at.
+pub fn current_db_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Initializes the database connection, creating the .sqlite file if needed, and upgrading the database
+/// if it's out of date. A copy of the database is kept next to it before any migration runs.
+pub fn initialize_database(app_dir: PathBuf) -> Result<Connection, ArsdError> {
+    fs::create_dir_all(&app_dir).expect("The app data directory should be created.");
+    let sqlite_path = app_dir.join("arsd.sqlite");
+
+    let mut db = Connection::open(&sqlite_path)?;
+    db.pragma_update(None, "journal_mode", "WAL")?;
+
+    let existing_user_version = user_version(&db)?;
+    if existing_user_version > 0 && existing_user_version < current_db_version() {
+        backup_database(&db, &sqlite_path, existing_user_version)?;
+    }
+
+    upgrade_database_if_needed(&mut db, existing_user_version)?;
+
+    Ok(db)
+}
+
+/// Open the database the app created without creating or migrating it, for tools that run next to
+/// the app. A database at another version is refused, since only the app may change the schema.
+pub fn open_existing(app_dir: &Path) -> Result<Connection, ArsdError> {
+    let sqlite_path = app_dir.join("arsd.sqlite");
+    let db = Connection::open_with_flags(
+        &sqlite_path,
+        OpenFlags::default().difference(OpenFlags::SQLITE_OPEN_CREATE),
+    )
+    .map_err(|e| ArsdError::Database(format!("Could not open {:?}: {}", sqlite_path, e)))?;
+    let version = user_version(&db)?;
+    if version != current_db_version() {
+        return Err(ArsdError::Database(format!(
+            "arsd.sqlite is at version {}, but this version of arsd expects {}. \
+            Open arsd, or update this tool to match it.",
+            version,
+            current_db_version()
+        )));
+    }
+    Ok(db)
+}
+
+fn user_version(db: &Connection) -> Result<u32, rusqlite::Error> {
+    db.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Write a consistent copy of the database (including anything still in the WAL) to
+/// `arsd.sqlite.v<version>.bak`, replacing an older backup of the same version.
+fn backup_database(db: &Connection, sqlite_path: &Path, version: u32) -> Result<(), ArsdError> {
+    let backup_path = sqlite_path.with_extension(format!("sqlite.v{}.bak", version));
+    match fs::remove_file(&backup_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    log::info!(
+        "Backing up database version {} to {:?} before migrating",
+        version,
+        backup_path
+    );
+    db.execute(
+        "VACUUM INTO ?1",
+        [backup_path.to_string_lossy().to_string()],
+    )?;
+    Ok(())
+}
+
+/// Upgrades the database to the current version, one migration at a time. Refuses to touch a
+/// database written by a newer version of arsd.
+pub fn upgrade_database_if_needed(
+    db: &mut Connection,
+    existing_version: u32,
+) -> Result<(), ArsdError> {
+    if existing_version > current_db_version() {
+        return Err(ArsdError::Database(format!(
+            "arsd.sqlite is at version {}, but this version of arsd only understands up to {}. \
+            Update arsd, or move arsd.sqlite aside to start over.",
+            existing_version,
+            current_db_version()
+        )));
+    }
 
+    for migration in MIGRATIONS.iter().filter(|m| m.version > existing_version) {
+        log::info!(
+            "Migrating database to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        let tx = db.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
         tx.commit()?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every version a released arsd could have left on disk, 0 being a brand new file.
+    fn historical_versions() -> Vec<u32> {
+        std::iter::once(0)
+            .chain(MIGRATIONS.iter().map(|m| m.version))
+            .collect()
+    }
+
+    /// Apply migrations up to `version`, leaving the schema the release at `version` created.
+    fn migrate_to(db: &mut Connection, version: u32) {
+        for migration in MIGRATIONS.iter().filter(|m| m.version <= version) {
+            let tx = db.transaction().unwrap();
+            tx.execute_batch(migration.sql).unwrap();
+            tx.pragma_update(None, "user_version", migration.version)
+                .unwrap();
+            tx.commit().unwrap();
+        }
+    }
+
+    fn database_at(version: u32) -> Connection {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate_to(&mut db, version);
+        db
+    }
+
+    fn column_not_null(db: &Connection, table: &str, column: &str) -> bool {
+        let mut statement = db
+            .prepare(&format!("PRAGMA table_info({})", table))
+            .unwrap();
+        let columns = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>("name")?,
+                    row.get::<_, bool>("notnull")?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        columns
+            .into_iter()
+            .find(|(name, _)| name == column)
+            .map(|(_, notnull)| notnull)
+            .unwrap_or_else(|| panic!("{}.{} should exist", table, column))
+    }
+
+    #[test]
+    fn migrations_are_ordered() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+    }
+
+    #[test]
+    fn migrates_from_every_historical_version() {
+        for version in historical_versions() {
+            let mut db = database_at(version);
+            if version >= 2 {
+                db.execute_batch(
+                    "INSERT INTO accounts (partition, account_id, email_address, account_name)
+                        VALUES ('us-east-1-d-123', '111122223333', 'a@example.com', 'sandbox');
+                    INSERT INTO roles (partition, account_id, role_name)
+                        VALUES ('us-east-1-d-123', '111122223333', 'ReadOnly');
+                    INSERT INTO history (partition, account, role, style)
+                        VALUES ('us-east-1-d-123', '111122223333', 'ReadOnly', 'WebConsole');",
+                )
+                .unwrap();
+            }
+            if (2..5).contains(&version) {
+                // allowed by the NOT_NULL typo, dropped when roles is rebuilt
+                db.execute(
+                    "INSERT INTO roles (partition, account_id, role_name)
+                        VALUES (NULL, '111122223333', 'Orphaned')",
+                    [],
+                )
+                .unwrap();
+            }
+
+            upgrade_database_if_needed(&mut db, version).unwrap();
+
+            assert_eq!(
+                user_version(&db).unwrap(),
+                current_db_version(),
+                "from version {}",
+                version
+            );
+            assert!(column_not_null(&db, "roles", "partition"));
+            assert!(!column_not_null(&db, "tokens", "refresh_token"));
+            assert!(!column_not_null(&db, "tokens", "client_secret"));
+            db.execute_batch("SELECT * FROM role_credentials").unwrap();
+
+            if version >= 2 {
+                let roles: Vec<String> = db
+                    .prepare("SELECT role_name FROM roles")
+                    .unwrap()
+                    .query_map([], |row| row.get(0))
+                    .unwrap()
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                assert_eq!(roles, vec!["ReadOnly"], "from version {}", version);
+                let accounts: u32 = db
+                    .query_row("SELECT count(*) FROM accounts", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(accounts, 1, "from version {}", version);
+            }
+        }
+    }
+
+    #[test]
+    fn upgrading_twice_is_a_no_op() {
+        let mut db = database_at(0);
+        upgrade_database_if_needed(&mut db, 0).unwrap();
+        upgrade_database_if_needed(&mut db, current_db_version()).unwrap();
+        assert_eq!(user_version(&db).unwrap(), current_db_version());
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let newer = current_db_version() + 1;
+        let mut db = database_at(current_db_version());
+        db.pragma_update(None, "user_version", newer).unwrap();
+
+        let err = upgrade_database_if_needed(&mut db, newer).unwrap_err();
+
+        assert!(matches!(err, ArsdError::Database(_)));
+        assert_eq!(user_version(&db).unwrap(), newer);
+    }
+
+    #[test]
+    fn open_existing_neither_creates_nor_migrates() {
+        let dir = std::env::temp_dir().join(format!("arsd-open-existing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        assert!(matches!(open_existing(&dir), Err(ArsdError::Database(_))));
+        assert!(!dir.join("arsd.sqlite").exists());
+
+        let mut db = Connection::open(dir.join("arsd.sqlite")).unwrap();
+        migrate_to(&mut db, current_db_version() - 1);
+        drop(db);
+        assert!(matches!(open_existing(&dir), Err(ArsdError::Database(_))));
+        let db = Connection::open(dir.join("arsd.sqlite")).unwrap();
+        assert_eq!(user_version(&db).unwrap(), current_db_version() - 1);
+
+        initialize_database(dir.clone()).unwrap();
+        assert!(open_existing(&dir).is_ok());
+    }
+
+    #[test]
+    fn backs_up_before_migrating() {
+        let dir = std::env::temp_dir().join(format!("arsd-migration-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        migrate_to(&mut Connection::open(dir.join("arsd.sqlite")).unwrap(), 2);
+
+        let db = initialize_database(dir.clone()).unwrap();
+        assert_eq!(user_version(&db).unwrap(), current_db_version());
+
+        let backup = Connection::open(dir.join("arsd.sqlite.v2.bak")).unwrap();
+        assert_eq!(user_version(&backup).unwrap(), 2);
+
+        drop(db);
+        drop(backup);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,16 +3,63 @@ use rusqlite::{named_params, Connection};
 use serde_derive::{Deserialize, Serialize};
 use serde_rusqlite as sq_serde;
 
+use crate::domain::RegistrationInfo;
+use crate::error::ArsdError;
+use crate::secrets::{secret_name, SecretStore};
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Token {
     pub partition: String,
     pub token_type: String,
     pub access_token: String,
     pub expires_at: DateTime<Utc>,
+    pub refresh_token: Option<String>,
+    /// the client registration the token was issued to, which is the only one that can refresh it
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
 }
 
 impl Token {
-    pub fn find(db: &Connection, partition: String) -> Result<Option<Token>, rusqlite::Error> {
+    fn sealed(&self, secrets: &SecretStore) -> Token {
+        let name = |column| secret_name("tokens", &[self.partition.as_str()], column);
+        Token {
+            partition: self.partition.clone(),
+            token_type: self.token_type.clone(),
+            access_token: secrets.seal(&name("access_token"), &self.access_token),
+            expires_at: self.expires_at,
+            refresh_token: self
+                .refresh_token
+                .as_ref()
+                .map(|t| secrets.seal(&name("refresh_token"), t)),
+            client_id: self.client_id.clone(),
+            client_secret: self
+                .client_secret
+                .as_ref()
+                .map(|s| secrets.seal(&name("client_secret"), s)),
+        }
+    }
+
+    fn unsealed(self, secrets: &SecretStore) -> Result<Token, ArsdError> {
+        let name = |column| secret_name("tokens", &[self.partition.as_str()], column);
+        Ok(Token {
+            access_token: secrets.unseal(&name("access_token"), &self.access_token)?,
+            refresh_token: match self.refresh_token.as_ref() {
+                Some(t) => Some(secrets.unseal(&name("refresh_token"), t)?),
+                None => None,
+            },
+            client_secret: match self.client_secret.as_ref() {
+                Some(s) => Some(secrets.unseal(&name("client_secret"), s)?),
+                None => None,
+            },
+            ..self
+        })
+    }
+
+    pub fn find(
+        db: &Connection,
+        secrets: &SecretStore,
+        partition: String,
+    ) -> Result<Option<Token>, rusqlite::Error> {
         let mut statement = db
             .prepare("SELECT * FROM tokens WHERE partition = :partition")
             .unwrap();
@@ -31,7 +78,10 @@ impl Token {
                             if i.expires_at < Utc::now() {
                                 continue;
                             }
-                            return Ok(Some(i));
+                            match i.unsealed(secrets) {
+                                Ok(t) => return Ok(Some(t)),
+                                Err(e) => log::error!("Could not read token: {}", e),
+                            }
                         }
                         Err(e) => {
                             log::error!("sql_serde error on token: {}", e);
@@ -43,15 +93,67 @@ impl Token {
         }
     }
 
-    pub fn insert(&self, db: &Connection) -> Result<(), rusqlite::Error> {
+    /// Find the partition's token even if the access token has expired, as long as it came with a
+    /// refresh token that can be used to renew it.
+    pub fn find_refreshable(
+        db: &Connection,
+        secrets: &SecretStore,
+        partition: String,
+    ) -> Result<Option<Token>, rusqlite::Error> {
+        let mut statement = db
+            .prepare(
+                "SELECT * FROM tokens WHERE partition = :partition AND refresh_token IS NOT NULL
+                ORDER BY expires_at DESC LIMIT 1",
+            )
+            .unwrap();
+        let rows = statement.query_and_then(
+            named_params! {":partition": partition},
+            sq_serde::from_row::<Token>,
+        );
+        match rows {
+            Err(e) => match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                _ => Err(e),
+            },
+            Ok(r) => match r.into_iter().next() {
+                Some(i) => match i
+                    .expect("Something went wrong with sql_serde")
+                    .unsealed(secrets)
+                {
+                    Ok(t) => Ok(Some(t)),
+                    Err(e) => {
+                        log::error!("Could not read token: {}", e);
+                        Ok(None)
+                    }
+                },
+                None => Ok(None),
+            },
+        }
+    }
+
+    pub fn delete(db: &Connection, partition: String) -> Result<(), rusqlite::Error> {
+        db.execute(
+            "DELETE FROM tokens WHERE partition = :partition",
+            named_params! {":partition": partition},
+        )?;
+        Ok(())
+    }
+
+    pub fn insert(&self, db: &Connection, secrets: &SecretStore) -> Result<(), rusqlite::Error> {
+        // a refresh grant doesn't always rotate the refresh token, keep the old one if so
         db.execute(
-            "INSERT INTO tokens (partition, token_type, access_token, expires_at)
-            VALUES (:partition, :token_type, :access_token, :expires_at)
+            "INSERT INTO tokens (partition, token_type, access_token, expires_at, refresh_token,
+                client_id, client_secret)
+            VALUES (:partition, :token_type, :access_token, :expires_at, :refresh_token,
+                :client_id, :client_secret)
             ON CONFLICT (partition, token_type) DO UPDATE SET
                 access_token = excluded.access_token,
-                expires_at = excluded.expires_at
+                expires_at = excluded.expires_at,
+                refresh_token = COALESCE(excluded.refresh_token, tokens.refresh_token),
+                client_id = COALESCE(excluded.client_id, tokens.client_id),
+                client_secret = COALESCE(excluded.client_secret, tokens.client_secret)
             ",
-            sq_serde::to_params_named(self)
+            sq_serde::to_params_named(self.sealed(secrets))
                 .unwrap()
                 .to_slice()
                 .as_slice(),
@@ -70,8 +172,47 @@ pub struct Registration {
 }
 
 impl Registration {
+    fn client_secret_name(&self) -> String {
+        secret_name("registrations", &[self.partition.as_str()], "client_secret")
+    }
+
+    /// Every stored registration, expired ones included, for the storage view.
+    pub fn list(db: &Connection) -> Result<Vec<RegistrationInfo>, rusqlite::Error> {
+        let mut statement = db
+            .prepare(
+                "SELECT partition, issued_at, expires_at FROM registrations ORDER BY partition",
+            )
+            .unwrap();
+        let rows = statement.query_and_then([], sq_serde::from_row::<RegistrationInfo>);
+        match rows {
+            Err(e) => match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(vec![]),
+                _ => Err(e),
+            },
+            Ok(r) => Ok(r
+                .into_iter()
+                .filter_map(|i| match i {
+                    Err(e) => {
+                        log::error!("sql_serde error on registration: {}", e);
+                        None
+                    }
+                    Ok(i) => Some(i),
+                })
+                .collect()),
+        }
+    }
+
+    pub fn delete(db: &Connection, partition: String) -> Result<(), rusqlite::Error> {
+        db.execute(
+            "DELETE FROM registrations WHERE partition = :partition",
+            named_params! {":partition": partition},
+        )?;
+        Ok(())
+    }
+
     pub fn find(
         db: &Connection,
+        secrets: &SecretStore,
         partition: String,
     ) -> Result<Option<Registration>, rusqlite::Error> {
         let mut statement = db.prepare(
@@ -86,13 +227,35 @@ impl Registration {
                 _ => Err(e),
             },
             Ok(r) => match r.into_iter().next() {
-                Some(i) => Ok(Some(i.expect("Something went wrong with sql_serde"))),
+                Some(i) => {
+                    let mut registration = i.expect("Something went wrong with sql_serde");
+                    match secrets.unseal(
+                        &registration.client_secret_name(),
+                        &registration.client_secret,
+                    ) {
+                        Ok(secret) => {
+                            registration.client_secret = secret;
+                            Ok(Some(registration))
+                        }
+                        Err(e) => {
+                            log::error!("Could not read client registration: {}", e);
+                            Ok(None)
+                        }
+                    }
+                }
                 None => Ok(None),
             },
         }
     }
 
-    pub fn insert(&self, db: &Connection) -> Result<(), rusqlite::Error> {
+    pub fn insert(&self, db: &Connection, secrets: &SecretStore) -> Result<(), rusqlite::Error> {
+        let sealed = Registration {
+            partition: self.partition.clone(),
+            client_id: self.client_id.clone(),
+            client_secret: secrets.seal(&self.client_secret_name(), &self.client_secret),
+            expires_at: self.expires_at,
+            issued_at: self.issued_at,
+        };
         db.execute(
             "INSERT INTO registrations (partition, client_id, client_secret, expires_at, issued_at) \
             VALUES (:partition, :client_id, :client_secret, :expires_at, :issued_at)
@@ -102,7 +265,7 @@ impl Registration {
                 expires_at = excluded.expires_at,
                 issued_at = excluded.issued_at
             ",
-            sq_serde::to_params_named(self
+            sq_serde::to_params_named(sealed
             )
                 .unwrap()
                 .to_slice()
@@ -111,3 +274,132 @@ impl Registration {
         Ok(())
     }
 }
+
+/// Role credentials from `GetRoleCredentials`, kept until they are close to expiring.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RoleCredentials {
+    pub partition: String,
+    pub account_id: String,
+    pub role_name: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl RoleCredentials {
+    fn secret_name(&self, column: &str) -> String {
+        secret_name(
+            "role_credentials",
+            &[
+                self.partition.as_str(),
+                self.account_id.as_str(),
+                self.role_name.as_str(),
+            ],
+            column,
+        )
+    }
+
+    fn sealed(&self, secrets: &SecretStore) -> RoleCredentials {
+        RoleCredentials {
+            partition: self.partition.clone(),
+            account_id: self.account_id.clone(),
+            role_name: self.role_name.clone(),
+            access_key_id: self.access_key_id.clone(),
+            secret_access_key: secrets.seal(
+                &self.secret_name("secret_access_key"),
+                &self.secret_access_key,
+            ),
+            session_token: secrets.seal(&self.secret_name("session_token"), &self.session_token),
+            expires_at: self.expires_at,
+        }
+    }
+
+    fn unsealed(self, secrets: &SecretStore) -> Result<RoleCredentials, ArsdError> {
+        Ok(RoleCredentials {
+            secret_access_key: secrets.unseal(
+                &self.secret_name("secret_access_key"),
+                &self.secret_access_key,
+            )?,
+            session_token: secrets
+                .unseal(&self.secret_name("session_token"), &self.session_token)?,
+            ..self
+        })
+    }
+
+    /// Find cached credentials that are still valid for at least `margin`.
+    pub fn find(
+        db: &Connection,
+        secrets: &SecretStore,
+        partition: String,
+        account_id: String,
+        role_name: String,
+        margin: chrono::Duration,
+    ) -> Result<Option<RoleCredentials>, rusqlite::Error> {
+        let mut statement = db
+            .prepare(
+                "SELECT * FROM role_credentials WHERE partition = :partition
+                AND account_id = :account_id AND role_name = :role_name LIMIT 1",
+            )
+            .unwrap();
+        let rows = statement.query_and_then(
+            named_params! {
+                ":partition": partition,
+                ":account_id": account_id,
+                ":role_name": role_name,
+            },
+            sq_serde::from_row::<RoleCredentials>,
+        );
+        match rows {
+            Err(e) => match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                _ => Err(e),
+            },
+            Ok(r) => match r.into_iter().next() {
+                Some(i) => {
+                    let creds = i.expect("Something went wrong with sql_serde");
+                    if creds.expires_at < Utc::now() + margin {
+                        return Ok(None);
+                    }
+                    match creds.unsealed(secrets) {
+                        Ok(c) => Ok(Some(c)),
+                        Err(e) => {
+                            log::error!("Could not read cached credentials: {}", e);
+                            Ok(None)
+                        }
+                    }
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    pub fn insert(&self, db: &Connection, secrets: &SecretStore) -> Result<(), rusqlite::Error> {
+        db.execute(
+            "INSERT INTO role_credentials (partition, account_id, role_name, access_key_id,
+                secret_access_key, session_token, expires_at)
+            VALUES (:partition, :account_id, :role_name, :access_key_id, :secret_access_key,
+                :session_token, :expires_at)
+            ON CONFLICT (partition, account_id, role_name) DO UPDATE SET
+                access_key_id = excluded.access_key_id,
+                secret_access_key = excluded.secret_access_key,
+                session_token = excluded.session_token,
+                expires_at = excluded.expires_at
+            ",
+            sq_serde::to_params_named(self.sealed(secrets))
+                .unwrap()
+                .to_slice()
+                .as_slice(),
+        )?;
+        Ok(())
+    }
+
+    /// Remove every cached credential, or only those for one partition.
+    pub fn delete(db: &Connection, partition: Option<String>) -> Result<(), rusqlite::Error> {
+        db.execute(
+            "DELETE FROM role_credentials WHERE :partition IS NULL OR partition = :partition",
+            named_params! {":partition": partition},
+        )?;
+        Ok(())
+    }
+}
@@ -21,9 +21,9 @@ pub struct HistoryNew {
 impl HistoryNew {
     pub fn insert(&self, db: &Connection) -> Result<(), rusqlite::Error> {
         db.execute(
-            "INSERT INTO history (partition, account, role, style) \
-            VALUES (:partition, :account, :role, :style)",
-            sq_serde::to_params_named_with_fields(self, &["partition", "account", "role", "style"])
+            "INSERT INTO history (partition, account, role, style, service) \
+            VALUES (:partition, :account, :role, :style, :service)",
+            sq_serde::to_params_named(self)
                 .unwrap()
                 .to_slice()
                 .as_slice(),
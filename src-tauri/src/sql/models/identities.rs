@@ -1,7 +1,8 @@
 use chrono::{DateTime, Utc};
-use rusqlite::{named_params, Connection, OptionalExtension};
+use rusqlite::{named_params, Connection};
 use serde_derive::{Deserialize, Serialize};
 use serde_rusqlite as sq_serde;
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Account {
@@ -39,40 +40,31 @@ impl Account {
         }
     }
 
-    pub fn score(db: &Connection, partition: String, account_id: String) -> Option<i64> {
+    /// Frecency of every used account in the partition, keyed by account ID: each use in
+    /// `history` counts 1 when it happens and loses half its weight every `half_life_days`, so
+    /// recent uses outrank old habits. Accounts that were never used are left out.
+    pub fn scores(
+        db: &Connection,
+        partition: String,
+        half_life_days: f64,
+    ) -> Result<HashMap<String, f64>, rusqlite::Error> {
         let mut statement = db
             .prepare(
-                "with ranks as (
-                    SELECT 
-                        t.account, (
-                            SELECT COUNT(*) + 1
-                            FROM (SELECT account, COUNT(*) AS frequency 
-                                  FROM history 
-                                  GROUP BY account) AS sub_t
-                            WHERE sub_t.frequency < t.frequency
-                        ) AS rank
-                    FROM (
-                        SELECT account, COUNT(*) AS frequency 
-                        FROM history 
-                        WHERE partition = :partition
-                        GROUP BY account) AS t
-                    )
-                SELECT rank FROM ranks WHERE account = :account_id",
+                "SELECT account, julianday('now') - julianday(timestamp) FROM history
+                WHERE partition = :partition",
             )
             .unwrap();
-        match statement
-            .query_row(
-                named_params! {":account_id": account_id, ":partition": partition},
-                |r| r.get(0),
-            )
-            .optional()
-        {
-            Err(e) => {
-                log::warn!("rank failed for account {}: {:?}", account_id, e);
-                None
-            }
-            Ok(r) => r,
+        let mut ages: HashMap<String, Vec<f64>> = HashMap::new();
+        for row in statement.query_map(named_params! {":partition": partition}, |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, f64>(1)?))
+        })? {
+            let (account_id, age) = row?;
+            ages.entry(account_id).or_default().push(age);
         }
+        Ok(ages
+            .into_iter()
+            .map(|(account_id, ages)| (account_id, frecency(&ages, half_life_days)))
+            .collect())
     }
 
     pub fn list(db: &Connection, partition: String) -> Result<Vec<Account>, rusqlite::Error> {
@@ -118,15 +110,32 @@ impl Account {
 
     pub fn as_info(&self) -> crate::domain::AccountInfo {
         crate::domain::AccountInfo {
+            partition: self.partition.clone(),
             account_id: self.account_id.clone(),
             account_name: self.account_name.clone(),
             email_address: self.email_address.clone(),
             alias: None,
             score: None,
+            tags: vec![],
         }
     }
 }
 
+fn frecency(ages_days: &[f64], half_life_days: f64) -> f64 {
+    ages_days
+        .iter()
+        .map(|age| 0.5_f64.powf(age.max(0.0) / half_life_days))
+        .sum()
+}
+
+/// How often and how recently a role was used, from `history`.
+#[derive(Debug, PartialEq)]
+pub struct RoleUsage {
+    pub score: Option<f64>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub use_count: i64,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Role {
     pub partition: String,
@@ -168,6 +177,48 @@ impl Role {
         }
     }
 
+    /// Frecency (see `Account::scores`), last use and number of uses of every used role in the
+    /// account, keyed by role name.
+    pub fn usages(
+        db: &Connection,
+        partition: String,
+        account_id: String,
+        half_life_days: f64,
+    ) -> Result<HashMap<String, RoleUsage>, rusqlite::Error> {
+        let mut statement = db
+            .prepare(
+                "SELECT role, julianday('now') - julianday(timestamp), timestamp FROM history
+                WHERE partition = :partition AND account = :account_id",
+            )
+            .unwrap();
+        let mut uses: HashMap<String, Vec<(f64, DateTime<Utc>)>> = HashMap::new();
+        for row in statement.query_map(
+            named_params! {":partition": partition, ":account_id": account_id},
+            |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, f64>(1)?,
+                    r.get::<_, DateTime<Utc>>(2)?,
+                ))
+            },
+        )? {
+            let (role_name, age, at) = row?;
+            uses.entry(role_name).or_default().push((age, at));
+        }
+        Ok(uses
+            .into_iter()
+            .map(|(role_name, uses)| {
+                let ages: Vec<f64> = uses.iter().map(|(age, _)| *age).collect();
+                let usage = RoleUsage {
+                    score: Some(frecency(&ages, half_life_days)),
+                    last_used_at: uses.iter().map(|(_, at)| *at).max(),
+                    use_count: uses.len() as i64,
+                };
+                (role_name, usage)
+            })
+            .collect())
+    }
+
     pub fn insert(&self, db: &Connection) -> Result<(), rusqlite::Error> {
         db.execute(
             "INSERT INTO roles (partition, account_id, role_name, updated_at)
@@ -198,6 +249,9 @@ impl Role {
             account_id: self.account_id.clone(),
             role_name: self.role_name,
             alias: None,
+            score: None,
+            last_used_at: None,
+            use_count: 0,
         }
     }
 }
@@ -1,8 +1,8 @@
 pub mod history;
 pub use history::{AssumeStyle, HistoryNew};
 pub mod creds;
-pub use creds::{Registration, Token};
+pub use creds::{Registration, RoleCredentials, Token};
 pub mod identities;
-pub use identities::{Account, Role};
+pub use identities::{Account, Role, RoleUsage};
 pub mod settings;
-pub use settings::{SettingSort, SortOrder};
+pub use settings::{SettingHalfLife, SettingSort, SortOrder};
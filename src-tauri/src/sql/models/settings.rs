@@ -1,4 +1,4 @@
-use rusqlite::{named_params, Connection};
+use rusqlite::{named_params, Connection, OptionalExtension};
 use serde_derive::{Deserialize, Serialize};
 use serde_rusqlite as sq_serde;
 
@@ -49,3 +49,37 @@ impl SettingSort {
         Ok(())
     }
 }
+
+const HALF_LIFE_KEY: &str = "FRECENCY_HALF_LIFE_DAYS";
+
+/// Days it takes a use of an account to lose half its weight in the frecency score.
+pub struct SettingHalfLife {
+    pub value: f64,
+}
+
+impl SettingHalfLife {
+    pub const DEFAULT_DAYS: f64 = 14.0;
+
+    pub fn get(db: &Connection) -> Result<f64, rusqlite::Error> {
+        let value: Option<String> = db
+            .query_row(
+                "SELECT value FROM settings WHERE key = :key",
+                named_params! {":key": HALF_LIFE_KEY},
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(value
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|v| *v > 0.0)
+            .unwrap_or(SettingHalfLife::DEFAULT_DAYS))
+    }
+
+    pub fn insert(&self, db: &Connection) -> Result<(), rusqlite::Error> {
+        db.execute(
+            "INSERT INTO settings (key, value) VALUES (:key, :value)
+            ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            named_params! {":key": HALF_LIFE_KEY, ":value": self.value.to_string()},
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+// matching ArsdError in error.rs
+export interface ArsdError {
+  kind:
+    | "UnknownPartition"
+    | "NoToken"
+    | "TokenExpired"
+    | "SsoThrottled"
+    | "SsoAccessDenied"
+    | "Sso"
+    | "Database"
+    | "Network"
+    | "Io"
+    | "Secret"
+    | "Config";
+  message: string;
+}
+
+function isArsdError(e: unknown): e is ArsdError {
+  return typeof e === "object" && e !== null && "kind" in e && "message" in e;
+}
+
+export function describeError(e: unknown): string {
+  if (!isArsdError(e)) {
+    return `${e}`;
+  }
+  switch (e.kind) {
+    case "UnknownPartition":
+      return `No partition found for ${e.message}`;
+    case "NoToken":
+      return `Not signed in to ${e.message}`;
+    case "TokenExpired":
+      return "SSO session expired, sign in again";
+    case "SsoThrottled":
+      return "SSO is throttling requests, try again in a moment";
+    case "SsoAccessDenied":
+      return `Access denied: ${e.message}`;
+    default:
+      return e.message;
+  }
+}
+
+// true when signing in to the partition again would fix the error
+export function needsLogin(e: unknown): boolean {
+  return isArsdError(e) && (e.kind === "NoToken" || e.kind === "TokenExpired");
+}
@@ -22,6 +22,9 @@ export interface Role {
   role_name: string;
   account_id: string;
   alias?: string;
+  score?: number;
+  last_used_at?: string;
+  use_count: number;
 }
 
 export interface Credentials {
@@ -30,12 +33,37 @@ export interface Credentials {
   session_token: string;
 }
 
+export interface ContainerServer {
+  port: number;
+  authorization_token: string;
+}
+
+export interface ActiveRole {
+  partition: string;
+  account_id: string;
+  role_name: string;
+}
+
+export interface ImdsInfo {
+  endpoint: string;
+  active_role?: ActiveRole;
+}
+
 export interface AccountInfo {
+  partition: string;
   account_id: string;
   account_name: string;
   email_address: string;
   alias?: string;
   score?: number;
+  tags: string[];
+}
+
+// matching ConfigProblem in configuration.rs
+export interface ConfigProblem {
+  severity: "Error" | "Warning";
+  path: string;
+  message: string;
 }
 
 export enum SortOrder {
@@ -47,22 +75,36 @@ export interface PartitionState {
   slug: string;
   confirmation?: Confirmation;
   expires_at?: Date;
+  // login state machine in session/login.rs, from partition_state events
+  state?:
+    | "Start"
+    | "Registered"
+    | "AwaitingConfirmation"
+    | "Ready"
+    | "Expired"
+    | "Failed";
+  message?: string;
 }
 
 export const useSessionStore = defineStore("session", {
   state: () => ({
     sort: SortOrder.Alphabetical,
-    confirmation: undefined as Confirmation | undefined,
-    expires_at: undefined as Date | undefined,
     search_term: undefined as string | undefined,
+    tag: undefined as string | undefined,
     partitions: {} as Record<string, PartitionState>,
+    imds: undefined as ImdsInfo | undefined,
+    // saving from the app would drop them, see config_file.rs
+    configHasComments: false,
   }),
   getters: {
     search: (state) => {
       return state.search_term;
     },
     expired: (state) => {
-      return state.expires_at && state.expires_at.getTime() < Date.now();
+      return (slug: string) => {
+        const expiresAt = state.partitions[slug]?.expires_at;
+        return expiresAt === undefined || expiresAt.getTime() < Date.now();
+      };
     },
   },
 });